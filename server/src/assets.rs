@@ -0,0 +1,102 @@
+//! Serves the webpack bundle, so the editor and the server share an origin
+
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::http::{empty, percent_decode};
+
+const INDEX: &str = "index.html";
+
+fn mime_type(path: &Path) -> &'static str {
+  let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+
+  match ext.as_deref() {
+    Some("html") | Some("htm") => "text/html; charset=utf-8",
+    Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+    Some("css") => "text/css; charset=utf-8",
+    Some("json") | Some("map") => "application/json",
+    Some("txt") => "text/plain; charset=utf-8",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("jpg") | Some("jpeg") => "image/jpeg",
+    Some("gif") => "image/gif",
+    Some("ico") => "image/x-icon",
+    Some("woff") => "font/woff",
+    Some("woff2") => "font/woff2",
+    Some("ttf") => "font/ttf",
+    Some("wasm") => "application/wasm",
+    _ => "application/octet-stream"
+  }
+}
+
+/// Maps a request path onto `root`, or `None` if it would escape it
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+  let decoded = percent_decode(path)?;
+
+  let mut ret = root.to_path_buf();
+  for component in Path::new(decoded.trim_start_matches('/')).components() {
+    match component {
+      Component::Normal(c) => ret.push(c),
+      Component::CurDir => {},
+      _ => return None
+    }
+  }
+
+  Some(ret)
+}
+
+/// Paths without an extension are routes in the single-page app rather than files
+fn is_route(path: &Path) -> bool {
+  path.extension().is_none()
+}
+
+/// Responds with the file under `root` named by the request `path`. Unknown routes get
+/// `index.html` so the client-side router can handle them.
+pub async fn serve(root: &Path, path: &str, headers: &HeaderMap) -> Response<Body> {
+  let mut file = match resolve(root, path) {
+    Some(file) => file,
+    None => return empty(StatusCode::NOT_FOUND)
+  };
+
+  if tokio::fs::metadata(&file).await.map(|m| m.is_dir()).unwrap_or(false) {
+    file.push(INDEX);
+  }
+
+  let metadata = match tokio::fs::metadata(&file).await {
+    Ok(metadata) if metadata.is_file() => metadata,
+    _ if is_route(&file) => {
+      file = root.join(INDEX);
+      match tokio::fs::metadata(&file).await {
+        Ok(metadata) => metadata,
+        Err(_) => return empty(StatusCode::NOT_FOUND)
+      }
+    },
+    _ => return empty(StatusCode::NOT_FOUND)
+  };
+
+  // Bundle names aren't content-hashed, so browsers must revalidate every time
+  let modified = metadata.modified().ok()
+    .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_secs())
+    .unwrap_or_default();
+  let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified);
+
+  let builder = Response::builder()
+    .header(header::CACHE_CONTROL, "no-cache")
+    .header(header::ETAG, &etag);
+
+  if matches!(headers.get(header::IF_NONE_MATCH), Some(v) if v.as_bytes() == etag.as_bytes()) {
+    return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+  }
+
+  match tokio::fs::read(&file).await {
+    Ok(contents) => builder
+      .header(header::CONTENT_TYPE, HeaderValue::from_static(mime_type(&file)))
+      .body(Body::from(contents))
+      .unwrap(),
+    Err(_) => empty(StatusCode::NOT_FOUND)
+  }
+}
@@ -0,0 +1,121 @@
+use super::{Backing, UserBacking, Project, WatchGuard};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
+
+use uuid::Uuid;
+
+use crate::fs::{Contents, EntryInfo, Folder};
+use crate::inc::Target;
+use crate::path::ProjectPath;
+use crate::metrics::backing as time;
+use crate::proto::{User, Ident, ProjectBrief, FileChangedNotification};
+
+/// Wraps another backing, recording how long each of its operations takes
+pub struct MeteredBacking<B> {
+  inner: B
+}
+
+impl<B: Backing> MeteredBacking<B> {
+  pub fn new(inner: B) -> Self {
+    Self {
+      inner
+    }
+  }
+}
+
+#[async_trait]
+impl<B: Backing> Backing for MeteredBacking<B> {
+  async fn login(&self, user: User) -> anyhow::Result<Box<dyn UserBacking>> {
+    let inner = time("login", self.inner.login(user)).await?;
+    Ok(Box::new(MeteredUserBacking { inner }))
+  }
+
+  async fn resume(&self, ident: Ident) -> anyhow::Result<Box<dyn UserBacking>> {
+    let inner = time("resume", self.inner.resume(ident)).await?;
+    Ok(Box::new(MeteredUserBacking { inner }))
+  }
+}
+
+struct MeteredUserBacking {
+  inner: Box<dyn UserBacking>
+}
+
+#[async_trait]
+impl UserBacking for MeteredUserBacking {
+  async fn projects(&self) -> anyhow::Result<Vec<ProjectBrief>> {
+    time("projects", self.inner.projects()).await
+  }
+
+  async fn create_project(&mut self, name: String) -> anyhow::Result<ProjectBrief> {
+    time("create_project", self.inner.create_project(name)).await
+  }
+
+  async fn delete_project(&mut self, uuid: Uuid) -> anyhow::Result<()> {
+    time("delete_project", self.inner.delete_project(uuid)).await
+  }
+
+  async fn open_project(&mut self, uuid: Uuid) -> anyhow::Result<Box<dyn Project>> {
+    let inner = time("open_project", self.inner.open_project(uuid)).await?;
+    Ok(Box::new(MeteredProject { inner }))
+  }
+
+  async fn close_project(&mut self, uuid: Uuid) -> anyhow::Result<()> {
+    time("close_project", self.inner.close_project(uuid)).await
+  }
+
+  async fn logout(&mut self) -> anyhow::Result<()> {
+    time("logout", self.inner.logout()).await
+  }
+}
+
+struct MeteredProject {
+  inner: Box<dyn Project>
+}
+
+#[async_trait]
+impl Project for MeteredProject {
+  async fn uuid(&self) -> anyhow::Result<Uuid> {
+    self.inner.uuid().await
+  }
+
+  async fn name(&self) -> anyhow::Result<String> {
+    time("name", self.inner.name()).await
+  }
+
+  async fn target(&self) -> anyhow::Result<Target> {
+    time("target", self.inner.target()).await
+  }
+
+  async fn root(&self) -> anyhow::Result<Folder> {
+    time("root", self.inner.root()).await
+  }
+
+  async fn list(&self, path: Option<ProjectPath>, depth: Option<usize>) -> anyhow::Result<Vec<EntryInfo>> {
+    time("list", self.inner.list(path, depth)).await
+  }
+
+  async fn mkdir(&mut self, path: ProjectPath) -> anyhow::Result<()> {
+    time("mkdir", self.inner.mkdir(path)).await
+  }
+
+  async fn save(&mut self, path: ProjectPath, contents: Contents) -> anyhow::Result<()> {
+    time("save", self.inner.save(path, contents)).await
+  }
+
+  async fn read(&self, path: ProjectPath) -> anyhow::Result<Contents> {
+    time("read", self.inner.read(path)).await
+  }
+
+  async fn delete(&mut self, path: ProjectPath) -> anyhow::Result<()> {
+    time("delete", self.inner.delete(path)).await
+  }
+
+  async fn flush(&mut self) -> anyhow::Result<()> {
+    time("flush", self.inner.flush()).await
+  }
+
+  async fn watch(&self, events: UnboundedSender<FileChangedNotification>) -> anyhow::Result<Option<WatchGuard>> {
+    time("watch", self.inner.watch(events)).await
+  }
+}
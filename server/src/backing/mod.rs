@@ -1,31 +1,63 @@
 use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedSender;
 use serde::{Serialize, Deserialize};
 
-use crate::fs::Folder;
+use crate::fs::{Contents, EntryInfo, Folder};
 
 use uuid::Uuid;
 
-use std::path::PathBuf;
 
 mod simple;
 mod aws;
+mod metered;
 
-use crate::proto::{User, ProjectBrief};
+pub use simple::SimpleBacking;
+pub use metered::MeteredBacking;
+
+use crate::proto::{User, Ident, ProjectBrief, FileChangedNotification};
+use crate::inc::Target;
+use crate::path::ProjectPath;
+
+/// Keeps a `Project::watch` running until dropped
+pub type WatchGuard = Box<dyn std::any::Any + Send>;
 
 #[async_trait]
-pub trait Project {
+pub trait Project: Send + Sync {
   async fn uuid(&self) -> anyhow::Result<Uuid>;
   async fn name(&self) -> anyhow::Result<String>;
+  /// The target selected in the project's manifest
+  async fn target(&self) -> anyhow::Result<Target>;
+  /// Every file and folder with its contents. Prefer `list` unless the contents are needed.
   async fn root(&self) -> anyhow::Result<Folder>;
+  /// Metadata of the entries in the folder at `path` (the root if `None`), `depth` levels
+  /// deep or the whole tree if `None`. See `EntryInfo::list`.
+  async fn list(&self, path: Option<ProjectPath>, depth: Option<usize>) -> anyhow::Result<Vec<EntryInfo>>;
+
+  // Implementations must not follow symlinks out of the project (see `ProjectPath::resolve`)
+  async fn mkdir(&mut self, path: ProjectPath) -> anyhow::Result<()>;
+  async fn save(&mut self, path: ProjectPath, contents: Contents) -> anyhow::Result<()>;  
+  async fn read(&self, path: ProjectPath) -> anyhow::Result<Contents>;
+  async fn delete(&mut self, path: ProjectPath) -> anyhow::Result<()>;
 
-  async fn mkdir(&mut self, path: PathBuf) -> anyhow::Result<()>;
-  async fn save(&mut self, path: PathBuf, contents: String) -> anyhow::Result<()>;  
+  /// Writes through any saves the backing has buffered. Called before the project is closed.
+  async fn flush(&mut self) -> anyhow::Result<()> {
+    Ok(())
+  }
+
+  /// Sends a notification to `events` whenever something changes the project's files,
+  /// until the guard is dropped. `None` if the backing can't tell.
+  async fn watch(&self, _events: UnboundedSender<FileChangedNotification>) -> anyhow::Result<Option<WatchGuard>> {
+    Ok(None)
+  }
 }
 
 #[async_trait]
-pub trait UserBacking {
+pub trait UserBacking: Send + Sync {
   async fn projects(&self) -> anyhow::Result<Vec<ProjectBrief>>;
 
+  async fn create_project(&mut self, name: String) -> anyhow::Result<ProjectBrief>;
+  async fn delete_project(&mut self, uuid: Uuid) -> anyhow::Result<()>;
+
   async fn open_project(&mut self, uuid: Uuid) -> anyhow::Result<Box<dyn Project>>;
   async fn close_project(&mut self, uuid: Uuid) -> anyhow::Result<()>;
 
@@ -33,6 +65,9 @@ pub trait UserBacking {
 }
 
 #[async_trait]
-pub trait Backing {
-  async fn login(&mut self, user: User) -> anyhow::Result<Box<dyn UserBacking>>;
+pub trait Backing: Send + Sync {
+  async fn login(&self, user: User) -> anyhow::Result<Box<dyn UserBacking>>;
+
+  /// Logs in as `ident` without credentials, e.g., from a verified session token
+  async fn resume(&self, ident: Ident) -> anyhow::Result<Box<dyn UserBacking>>;
 }
\ No newline at end of file
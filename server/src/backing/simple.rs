@@ -1,19 +1,32 @@
-use super::{Backing, UserBacking, Project};
+use super::{Backing, UserBacking, Project, WatchGuard};
 
 use async_trait::async_trait;
+use lazy_static::lazy_static;
+use tokio::sync::mpsc::UnboundedSender;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use uuid::Uuid;
 
 use serde::{Serialize, Deserialize};
 
-use crate::fs::{Folder, File, Entry};
+use crate::fs::{Folder, File, Entry, EntryInfo, Contents};
 
-use crate::proto::{User, Ident, ProjectBrief};
+use crate::proto::{User, Ident, ProjectBrief, FileChange, FileChangedNotification};
+use crate::inc::Target;
+use crate::path::{PathError, ProjectPath};
+
+/// The backing's own bookkeeping at the root of each project, which clients may not touch
+const MANIFEST: &str = "manifest.json";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
-  name: String
+  name: String,
+  #[serde(default)]
+  target: Target
 }
 
 impl Manifest {
@@ -21,12 +34,141 @@ impl Manifest {
     let contents = tokio::fs::read_to_string(path).await?;
     Ok(serde_json::from_str(contents.as_str())?)
   }
+
+  pub async fn write<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+    tokio::fs::write(path, serde_json::to_string_pretty(self)?).await?;
+    Ok(())
+  }
+}
+
+/// How long the watcher waits for a burst of events on a path to settle
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// The changes a watcher event amounts to, with absolute paths
+fn changes(event: DebouncedEvent) -> Vec<(PathBuf, FileChange)> {
+  match event {
+    DebouncedEvent::Create(path) => vec![(path, FileChange::Created)],
+    DebouncedEvent::Write(path) => vec![(path, FileChange::Modified)],
+    DebouncedEvent::Remove(path) => vec![(path, FileChange::Deleted)],
+    DebouncedEvent::Rename(from, to) => vec![(from, FileChange::Deleted), (to, FileChange::Created)],
+    DebouncedEvent::Error(e, path) => {
+      tracing::warn!("Error watching {:?}: {}", path, e);
+      vec![]
+    },
+    _ => vec![]
+  }
+}
+
+lazy_static! {
+  /// Watchers by canonical project root, shared while any connection is subscribed
+  static ref WATCHES: Mutex<HashMap<PathBuf, Weak<SharedWatch>>> = Mutex::new(HashMap::new());
+}
+
+/// Watches one project for every connection that has it open. Inotify instances are
+/// limited per user (to 128 by default on Linux), so a watcher per connection won't do.
+struct SharedWatch {
+  root: PathBuf,
+  subscribers: Arc<Mutex<Subscribers>>,
+  /// Only kept so it keeps running. The mutex makes it `Sync`.
+  _watcher: Mutex<RecommendedWatcher>
+}
+
+#[derive(Default)]
+struct Subscribers {
+  next_id: u64,
+  senders: HashMap<u64, UnboundedSender<FileChangedNotification>>
+}
+
+impl SharedWatch {
+  /// The watch already running on `root`, or a new one
+  fn get(project: Uuid, root: PathBuf) -> anyhow::Result<Arc<Self>> {
+    let mut watches = WATCHES.lock().unwrap();
+    if let Some(watch) = watches.get(&root).and_then(Weak::upgrade) {
+      return Ok(watch);
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(tx, WATCH_DEBOUNCE)?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+
+    let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+    let relay_root = root.clone();
+    let relay_subscribers = subscribers.clone();
+
+    // Ends once the watcher is dropped and hangs up
+    std::thread::spawn(move || {
+      for event in rx {
+        for (path, change) in changes(event) {
+          // The manifest is the backing's own bookkeeping, not one of the project's files
+          let path = match path.strip_prefix(&relay_root).map(ProjectPath::new) {
+            Ok(Ok(path)) if path.as_ref() != Path::new(MANIFEST) => path,
+            _ => continue
+          };
+
+          let notification = FileChangedNotification { project, path, change };
+          relay_subscribers.lock().unwrap().senders
+            .retain(|_, events| events.send(notification.clone()).is_ok());
+        }
+      }
+    });
+
+    let watch = Arc::new(Self {
+      root: root.clone(),
+      subscribers,
+      _watcher: Mutex::new(watcher)
+    });
+    watches.insert(root, Arc::downgrade(&watch));
+    Ok(watch)
+  }
+
+  fn subscribe(self: Arc<Self>, events: UnboundedSender<FileChangedNotification>) -> Subscription {
+    let mut subscribers = self.subscribers.lock().unwrap();
+    let id = subscribers.next_id;
+    subscribers.next_id += 1;
+    subscribers.senders.insert(id, events);
+    drop(subscribers);
+
+    Subscription { watch: self, id }
+  }
+}
+
+impl Drop for SharedWatch {
+  fn drop(&mut self) {
+    let mut watches = WATCHES.lock().unwrap();
+    // Unless a new watch on the same root has already taken its place
+    if matches!(watches.get(&self.root), Some(watch) if watch.strong_count() == 0) {
+      watches.remove(&self.root);
+    }
+  }
+}
+
+/// A connection's interest in a `SharedWatch`, which stops once the last one is dropped
+struct Subscription {
+  watch: Arc<SharedWatch>,
+  id: u64
+}
+
+impl Drop for Subscription {
+  fn drop(&mut self) {
+    self.watch.subscribers.lock().unwrap().senders.remove(&self.id);
+  }
 }
 
 pub struct SimpleProject {
   path: PathBuf
 }
 
+impl SimpleProject {
+  /// Where a client's `path` is on disk, unless it's the manifest or inside it
+  async fn resolve(&self, path: &ProjectPath) -> anyhow::Result<PathBuf> {
+    if path.starts_with(MANIFEST) {
+      return Err(PathError::Reserved(path.into()).into());
+    }
+
+    path.resolve(&self.path).await
+  }
+}
+
 #[async_trait]
 impl Project for SimpleProject {
   async fn uuid(&self) -> anyhow::Result<Uuid> {
@@ -35,24 +177,57 @@ impl Project for SimpleProject {
   }
 
   async fn name(&self) -> anyhow::Result<String> {
-    Ok(Manifest::read(self.path.join("manifest.json")).await?.name)
+    Ok(Manifest::read(self.path.join(MANIFEST)).await?.name)
+  }
+
+  async fn target(&self) -> anyhow::Result<Target> {
+    Ok(Manifest::read(self.path.join(MANIFEST)).await?.target)
   }
 
   async fn root(&self) -> anyhow::Result<Folder> {
-    Ok(Folder::read(self.path).await?)
+    let mut ret = Folder::read(self.path.clone()).await?;
+    ret.remove(MANIFEST);
+    Ok(ret)
   }
 
-  async fn mkdir(&mut self, path: PathBuf) -> anyhow::Result<()> {
-    let mut root = self.path.clone();
-    root.push(path);
-    tokio::fs::create_dir_all(root).await?;
+  async fn list(&self, path: Option<ProjectPath>, depth: Option<usize>) -> anyhow::Result<Vec<EntryInfo>> {
+    match path {
+      Some(path) => EntryInfo::list(self.resolve(&path).await?, depth).await,
+      None => {
+        let mut ret = EntryInfo::list(self.path.clone(), depth).await?;
+        ret.retain(|entry| entry.name != MANIFEST);
+        Ok(ret)
+      }
+    }
+  }
+
+  async fn mkdir(&mut self, path: ProjectPath) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(self.resolve(&path).await?).await?;
     Ok(())
   }
 
-  async fn save(&mut self, path: PathBuf, contents: String) -> anyhow::Result<()> {
-    tokio::fs::write(path, contents).await?;
+  async fn save(&mut self, path: ProjectPath, contents: Contents) -> anyhow::Result<()> {
+    tokio::fs::write(self.resolve(&path).await?, contents.into_bytes()).await?;
     Ok(())
   }
+
+  async fn read(&self, path: ProjectPath) -> anyhow::Result<Contents> {
+    Ok(Contents::from_bytes(tokio::fs::read(self.resolve(&path).await?).await?))
+  }
+
+  async fn delete(&mut self, path: ProjectPath) -> anyhow::Result<()> {
+    tokio::fs::remove_file(self.resolve(&path).await?).await?;
+    Ok(())
+  }
+
+  async fn watch(&self, events: UnboundedSender<FileChangedNotification>) -> anyhow::Result<Option<WatchGuard>> {
+    let project = self.uuid().await?;
+    // Events carry canonical paths on some platforms
+    let root = tokio::fs::canonicalize(&self.path).await?;
+
+    let watch = tokio::task::spawn_blocking(move || SharedWatch::get(project, root)).await??;
+    Ok(Some(Box::new(watch.subscribe(events))))
+  }
 }
 
 pub struct SimpleUserBacking {
@@ -64,18 +239,40 @@ impl UserBacking for SimpleUserBacking {
   async fn projects(&self) -> anyhow::Result<Vec<ProjectBrief>> {
     let mut ret = Vec::new();
     
-    let read_dir = tokio::fs::read_dir(self.path).await?;
+    let mut read_dir = tokio::fs::read_dir(&self.path).await?;
     
     while let Ok(Some(entry)) = read_dir.next_entry().await {
       ret.push(ProjectBrief {
         uuid: Uuid::parse_str(&entry.file_name().into_string().unwrap())?,
-        name: Manifest::read(self.path.join("manifest.json")).await?.name
+        name: Manifest::read(entry.path().join(MANIFEST)).await?.name
       });
     }
 
     Ok(ret)
   }
 
+  async fn create_project(&mut self, name: String) -> anyhow::Result<ProjectBrief> {
+    let uuid = Uuid::new_v4();
+    let path = self.path.join(format!("{}", uuid));
+    tokio::fs::create_dir_all(&path).await?;
+
+    let manifest = Manifest {
+      name: name.clone(),
+      target: Target::default()
+    };
+    manifest.write(path.join(MANIFEST)).await?;
+
+    Ok(ProjectBrief {
+      uuid,
+      name
+    })
+  }
+
+  async fn delete_project(&mut self, uuid: Uuid) -> anyhow::Result<()> {
+    tokio::fs::remove_dir_all(self.path.join(format!("{}", uuid))).await?;
+    Ok(())
+  }
+
   async fn open_project(&mut self, uuid: Uuid) -> anyhow::Result<Box<dyn Project>> {
     Ok(Box::new(SimpleProject {
       path: self.path.join(format!("{}", uuid))
@@ -95,11 +292,98 @@ pub struct SimpleBacking {
   path: PathBuf
 }
 
+impl SimpleBacking {
+  /// A backing storing every project as a folder under `path`
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    Self {
+      path: path.into()
+    }
+  }
+}
+
 #[async_trait]
 impl Backing for SimpleBacking {
-  async fn login(&mut self, user: User) -> anyhow::Result<Box<dyn UserBacking>> {
+  async fn login(&self, user: User) -> anyhow::Result<Box<dyn UserBacking>> {
+    Ok(Box::new(SimpleUserBacking {
+      path: self.path.clone()
+    }))
+  }
+
+  async fn resume(&self, ident: Ident) -> anyhow::Result<Box<dyn UserBacking>> {
     Ok(Box::new(SimpleUserBacking {
       path: self.path.clone()
     }))
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A scratch storage root, removed when dropped
+  struct Scratch(PathBuf);
+
+  impl Scratch {
+    fn new() -> Self {
+      let path = std::env::temp_dir().join(format!("ivygate-simple-{}", Uuid::new_v4()));
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+    }
+
+    async fn project(&self) -> Box<dyn Project> {
+      let mut user = SimpleUserBacking { path: self.0.clone() };
+      let uuid = user.create_project("test".into()).await.unwrap().uuid;
+      user.open_project(uuid).await.unwrap()
+    }
+  }
+
+  impl Drop for Scratch {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn reserved<T: std::fmt::Debug>(result: anyhow::Result<T>) -> bool {
+    matches!(result.unwrap_err().downcast_ref::<PathError>(), Some(PathError::Reserved(_)))
+  }
+
+  #[tokio::test]
+  async fn reserves_the_manifest() {
+    let scratch = Scratch::new();
+    let mut project = scratch.project().await;
+    let manifest = ProjectPath::new(MANIFEST).unwrap();
+
+    assert!(reserved(project.read(manifest.clone()).await));
+    assert!(reserved(project.save(manifest.clone(), Contents::Text("{}".into())).await));
+    assert!(reserved(project.delete(manifest.clone()).await));
+    assert!(reserved(project.mkdir(ProjectPath::new("manifest.json/src").unwrap()).await));
+    assert!(reserved(project.list(Some(manifest), None).await));
+
+    // Untouched by any of that
+    assert_eq!(project.name().await.unwrap(), "test");
+
+    // Only at the root
+    project.mkdir(ProjectPath::new("src").unwrap()).await.unwrap();
+    let nested = ProjectPath::new("src/manifest.json").unwrap();
+    project.save(nested.clone(), Contents::Text("{}".into())).await.unwrap();
+    assert_eq!(project.read(nested).await.unwrap(), Contents::Text("{}".into()));
+  }
+
+  #[tokio::test]
+  async fn hides_the_manifest() {
+    let scratch = Scratch::new();
+    let mut project = scratch.project().await;
+    project.save(ProjectPath::new("main.c").unwrap(), Contents::Text("int main() {}".into())).await.unwrap();
+
+    let entries = project.list(None, None).await.unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, ["main.c"]);
+
+    // So it doesn't count towards the project's size either
+    assert_eq!(crate::fs::file_sizes(&entries), [(PathBuf::from("main.c"), 13)]);
+
+    let root = serde_json::to_value(project.root().await.unwrap()).unwrap();
+    assert!(root["entries"].get(MANIFEST).is_none());
+    assert!(root["entries"].get("main.c").is_some());
+  }
 }
\ No newline at end of file
@@ -0,0 +1,215 @@
+use serde::{Serialize, Deserialize};
+
+use tungstenite::Message;
+
+use crate::proto::{Msg, Frame};
+
+/// How protocol messages are encoded on the wire. JSON is sent in text frames,
+/// everything else in binary frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+  Json,
+  Msgpack,
+  Cbor
+}
+
+impl Default for Encoding {
+  fn default() -> Self {
+    Self::Json
+  }
+}
+
+impl Encoding {
+  /// Picks the client's most preferred encoding, falling back to JSON
+  pub fn negotiate(preferences: &[Encoding]) -> Self {
+    preferences.first().copied().unwrap_or_default()
+  }
+
+  pub fn is_binary(&self) -> bool {
+    *self != Self::Json
+  }
+
+  pub fn encode(&self, msg: &Msg) -> anyhow::Result<Message> {
+    Ok(match self {
+      Self::Json => Message::Text(serde_json::to_string(msg)?),
+      // Named maps keep the structure identical to the JSON encoding
+      Self::Msgpack => Message::Binary(rmp_serde::to_vec_named(msg)?),
+      Self::Cbor => {
+        let mut ret = Vec::new();
+        ciborium::ser::into_writer(msg, &mut ret)?;
+        Message::Binary(ret)
+      }
+    })
+  }
+
+  /// Decodes the contents of a binary frame. Binary encodings are decoded straight into
+  /// a `Frame`, so byte strings arrive intact, e.g., as binary `Contents`.
+  pub fn decode(&self, data: &[u8]) -> anyhow::Result<Frame> {
+    Ok(match self {
+      Self::Json => Frame::from_value(serde_json::from_slice(data)?)?,
+      Self::Msgpack => rmp_serde::from_slice(data)?,
+      Self::Cbor => ciborium::de::from_reader(data)?
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::path::Path;
+
+  use uuid::Uuid;
+
+  use crate::fs::Contents;
+  use crate::path::ProjectPath;
+  use crate::proto::{
+    Batch, CreateFileReq, Error, ErrorCode, FileChange, FileChangedNotification, Notification, ProtocolError, Req, ReqKind
+  };
+
+  const BINARY: &[Encoding] = &[Encoding::Msgpack, Encoding::Cbor];
+
+  /// Encodes a frame as a client using `encoding` would
+  fn client_encode<T: Serialize>(encoding: Encoding, value: &T) -> Vec<u8> {
+    match encoding {
+      Encoding::Json => serde_json::to_vec(value).unwrap(),
+      Encoding::Msgpack => rmp_serde::to_vec_named(value).unwrap(),
+      Encoding::Cbor => {
+        let mut ret = Vec::new();
+        ciborium::ser::into_writer(value, &mut ret).unwrap();
+        ret
+      }
+    }
+  }
+
+  fn create_file(id: u64, contents: Contents) -> Req {
+    Req {
+      id,
+      kind: ReqKind::CreateFile(CreateFileReq {
+        project: Uuid::nil(),
+        path: ProjectPath::new("data.bin").unwrap(),
+        contents: Some(contents)
+      })
+    }
+  }
+
+  #[test]
+  fn negotiates_the_first_preference() {
+    assert_eq!(Encoding::negotiate(&[]), Encoding::Json);
+    assert_eq!(Encoding::negotiate(&[Encoding::Cbor, Encoding::Msgpack]), Encoding::Cbor);
+    assert_eq!(Encoding::negotiate(&[Encoding::Json, Encoding::Cbor]), Encoding::Json);
+  }
+
+  /// Decodes a frame the server sent, as a client would
+  fn client_decode(encoding: Encoding, msg: Message) -> serde_json::Value {
+    match (encoding, msg) {
+      (Encoding::Json, Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+      (Encoding::Msgpack, Message::Binary(data)) => rmp_serde::from_slice(&data).unwrap(),
+      (Encoding::Cbor, Message::Binary(data)) => ciborium::de::from_reader(data.as_slice()).unwrap(),
+      (encoding, msg) => panic!("{:?} encoded as {:?}", encoding, msg)
+    }
+  }
+
+  #[test]
+  fn encodes_json_as_text_and_the_rest_as_binary() {
+    let msg = Msg::from(ProtocolError {
+      id: Some(3),
+      error: Error::new(ErrorCode::MalformedRequest, "Bad request")
+    });
+    let expected = serde_json::to_value(&msg).unwrap();
+
+    // Same structure in every encoding, field names included
+    for encoding in &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+      assert_eq!(client_decode(*encoding, encoding.encode(&msg).unwrap()), expected, "{:?}", encoding);
+    }
+  }
+
+  #[test]
+  fn encodes_uuids_as_strings() {
+    let project = Uuid::new_v4();
+    let msg = Msg::from(Notification::from(FileChangedNotification {
+      project,
+      path: ProjectPath::new("main.c").unwrap(),
+      change: FileChange::Modified
+    }));
+
+    for encoding in &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+      let decoded = client_decode(*encoding, encoding.encode(&msg).unwrap());
+      assert_eq!(decoded["notification"]["project"], project.to_string(), "{:?}", encoding);
+    }
+  }
+
+  #[test]
+  fn decodes_uuids_from_strings() {
+    let project = Uuid::new_v4();
+    let req = serde_json::json!({
+      "id": 1,
+      "kind": { "type": "open_project", "uuid": project.to_string() }
+    });
+
+    for encoding in &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+      match encoding.decode(&client_encode(*encoding, &req)).unwrap() {
+        Frame::Req(Req { kind: ReqKind::OpenProject(open), .. }) => assert_eq!(open.uuid, project, "{:?}", encoding),
+        frame => panic!("{:?} decoded {:?}", encoding, frame)
+      }
+    }
+  }
+
+  #[test]
+  fn decodes_requests_and_batches() {
+    for encoding in &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+      let req = create_file(1, Contents::Text("int main() {}".into()));
+      match encoding.decode(&client_encode(*encoding, &req)).unwrap() {
+        Frame::Req(Req { id: 1, kind: ReqKind::CreateFile(create) }) => {
+          assert_eq!(create.contents, Some(Contents::Text("int main() {}".into())));
+          assert_eq!(create.path.as_ref(), Path::new("data.bin"));
+        },
+        frame => panic!("{:?} decoded {:?}", encoding, frame)
+      }
+
+      let reqs = vec![create_file(1, Contents::default()), create_file(2, Contents::default())];
+      match encoding.decode(&client_encode(*encoding, &reqs)).unwrap() {
+        Frame::Batch(batch) => {
+          assert_eq!(batch.reqs.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2]);
+          assert!(!batch.independent);
+        },
+        frame => panic!("{:?} decoded {:?}", encoding, frame)
+      }
+
+      let batch = Batch {
+        reqs: vec![create_file(3, Contents::default())],
+        independent: true
+      };
+      match encoding.decode(&client_encode(*encoding, &batch)).unwrap() {
+        Frame::Batch(batch) => assert!(batch.independent && batch.reqs[0].id == 3),
+        frame => panic!("{:?} decoded {:?}", encoding, frame)
+      }
+    }
+  }
+
+  #[test]
+  fn decodes_raw_bytes_in_binary_encodings() {
+    // Not UTF-8, so it can only be binary
+    let bytes = vec![0u8, 159, 146, 150, 255];
+
+    for encoding in BINARY {
+      let data = client_encode(*encoding, &create_file(1, Contents::Binary(bytes.clone())));
+
+      match encoding.decode(&data).unwrap() {
+        Frame::Req(Req { kind: ReqKind::CreateFile(create), .. }) => {
+          assert_eq!(create.contents, Some(Contents::Binary(bytes.clone())), "{:?}", encoding);
+        },
+        frame => panic!("{:?} decoded {:?}", encoding, frame)
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_garbage() {
+    for encoding in &[Encoding::Json, Encoding::Msgpack, Encoding::Cbor] {
+      assert!(encoding.decode(&[0xc1, 0xff, 0x00]).is_err(), "{:?}", encoding);
+      assert!(encoding.decode(&client_encode(*encoding, &42u64)).is_err(), "{:?}", encoding);
+    }
+  }
+}
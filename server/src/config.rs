@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context;
+use clap::Parser;
+use derive_more::*;
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::inc::INCS;
+use crate::tls::TlsConfig;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The server's configuration. Must not be called before `init`.
+pub fn get() -> &'static Config {
+  CONFIG.get().expect("configuration is not initialized")
+}
+
+pub fn init(config: Config) {
+  let _ = CONFIG.set(config);
+}
+
+/// Command-line arguments. These override the configuration file.
+#[derive(Debug, Parser)]
+#[clap(name = "ivygate-server", version, about = "Incremental compilation and project server for ivygate")]
+pub struct Args {
+  /// TOML configuration file
+  #[clap(short, long, env = "IVYGATE_CONFIG")]
+  pub config: Option<PathBuf>,
+
+  /// Address to listen on
+  #[clap(short, long, env = "IVYGATE_BIND")]
+  pub bind: Option<SocketAddr>,
+
+  /// Folder to store projects in, using the simple backing
+  #[clap(long, env = "IVYGATE_STORAGE_ROOT")]
+  pub storage_root: Option<PathBuf>,
+
+  /// Folder containing the webpack bundle to serve alongside the API
+  #[clap(long, env = "IVYGATE_STATIC_DIR")]
+  pub static_dir: Option<PathBuf>,
+
+  /// PEM certificate chain. Serves wss:// when given with --tls-key.
+  #[clap(long, env = "IVYGATE_TLS_CERT")]
+  pub tls_cert: Option<PathBuf>,
+
+  /// PEM private key for --tls-cert
+  #[clap(long, env = "IVYGATE_TLS_KEY")]
+  pub tls_key: Option<PathBuf>,
+
+  /// One of off, error, warn, info, debug or trace. RUST_LOG takes precedence.
+  #[clap(long, env = "IVYGATE_LOG_LEVEL")]
+  pub log_level: Option<LevelFilter>,
+
+  /// text or json
+  #[clap(long, env = "IVYGATE_LOG_FORMAT")]
+  pub log_format: Option<LogFormat>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+  /// Human-readable lines
+  Text,
+  /// One JSON object per event, including the spans it happened in
+  Json
+}
+
+impl Default for LogFormat {
+  fn default() -> Self {
+    Self::Text
+  }
+}
+
+impl FromStr for LogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(Self::Text),
+      "json" => Ok(Self::Json),
+      _ => Err(format!("Unknown log format \"{}\" (expected text or json)", s))
+    }
+  }
+}
+
+#[derive(Display, Debug, Error)]
+pub enum ConfigError {
+  #[display(fmt = "Unknown incremental compiler \"{}\" (expected one of {:?})", _0, INCS)]
+  UnknownInc(#[error(ignore)] String),
+  #[display(fmt = "TLS requires both a certificate and a key")]
+  IncompleteTls,
+  #[display(fmt = "{} does not exist", "_0.display()")]
+  Missing(#[error(ignore)] PathBuf),
+  #[display(fmt = "connection.max_bad_frames must be at least 1")]
+  NoBadFrames,
+  #[display(fmt = "connection.pong_timeout must be at least 1 when pings are enabled")]
+  NoPongTimeout,
+  #[display(fmt = "limits.requests_per_second and limits.request_burst must be positive")]
+  NoRequests,
+  #[display(fmt = "auth.secret must be a real secret; leave it out to generate one at startup")]
+  PlaceholderSecret
+}
+
+/// Secrets copied from examples rather than chosen, which anyone could sign tokens with
+const PLACEHOLDER_SECRETS: &[&str] = &["change me", "changeme", "secret"];
+
+/// Where projects are stored
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BackingConfig {
+  /// Every project is a folder under `root` on the local disk
+  Simple {
+    root: PathBuf
+  }
+}
+
+impl Default for BackingConfig {
+  fn default() -> Self {
+    Self::Simple {
+      root: PathBuf::from("projects")
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IncConfig {
+  pub enabled: bool,
+
+  /// Extra arguments passed to the compiler for every session
+  pub flags: Vec<String>
+}
+
+impl Default for IncConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      flags: Vec::new()
+    }
+  }
+}
+
+/// Per-user and per-connection quotas (see `quota`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+  /// Live compiler sessions per connection
+  pub max_sessions: usize,
+
+  /// Open files per connection, whether or not they have a compiler session.
+  /// Each one keeps the file's contents in memory.
+  pub max_handles: usize,
+
+  /// Live compiler sessions per user, across all of their connections
+  pub max_user_sessions: usize,
+
+  /// Files compiling at once per user
+  pub max_concurrent_builds: usize,
+
+  /// Bytes in a single file
+  pub max_file_size: u64,
+
+  /// Bytes in all of a project's files together
+  pub max_project_size: u64,
+
+  /// Sustained requests per second per user, across all of their connections.
+  /// Each connection has a bucket of its own until it logs in.
+  pub requests_per_second: f64,
+
+  /// Requests a user may send at once before `requests_per_second` applies
+  pub request_burst: u32
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      max_sessions: 32,
+      max_handles: 64,
+      max_user_sessions: 64,
+      max_concurrent_builds: 4,
+      max_file_size: 1 << 20,
+      max_project_size: 64 << 20,
+      requests_per_second: 20.0,
+      request_burst: 50
+    }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+  /// Key signing session tokens. Without one, a random key is generated at startup.
+  pub secret: Option<String>,
+
+  /// Seconds a session token stays valid after login
+  pub token_ttl: u64
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    Self {
+      secret: None,
+      token_ttl: 12 * 60 * 60
+    }
+  }
+}
+
+/// Settings applied to every client connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+  /// Number of malformed frames tolerated before the connection is closed
+  pub max_bad_frames: usize,
+
+  /// Seconds a logged-in user's open files outlive their connection, waiting to be
+  /// reattached by a `ResumeReq`. 0 closes them immediately.
+  pub reattach_grace: u64,
+
+  /// Seconds between pings to the client. 0 disables pings and dead-peer detection.
+  pub ping_interval: u64,
+
+  /// Seconds to wait for a pong (or any other frame) after a ping before dropping the client
+  pub pong_timeout: u64,
+
+  /// Seconds a connection may go without a request before it logs in (or initializes, over LSP).
+  /// 0 never times out.
+  pub login_timeout: u64,
+
+  /// Seconds a logged-in connection may go without a request. 0 never times out.
+  pub idle_timeout: u64,
+
+  /// Origins whose pages may open websockets, e.g., "https://ide.example.edu", or "*" for any.
+  /// Pages served by this server are allowed when it's reached through `localhost` or an IP
+  /// address. Clients that send no `Origin` (i.e., anything but a browser) are always allowed.
+  pub allowed_origins: Vec<String>
+}
+
+impl Default for ConnectionConfig {
+  fn default() -> Self {
+    Self {
+      max_bad_frames: 10,
+      reattach_grace: 60,
+      ping_interval: 20,
+      pong_timeout: 20,
+      login_timeout: 30,
+      idle_timeout: 60 * 60,
+      allowed_origins: Vec::new()
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetsConfig {
+  /// System root containing the Wombat's headers and libraries
+  pub wombat_sysroot: Option<PathBuf>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub bind: SocketAddr,
+  pub backing: BackingConfig,
+
+  /// Scratch space for compiler sessions. Each server process works in a folder of its
+  /// own inside it, and removes only that.
+  pub tmp_dir: PathBuf,
+
+  /// Per-compiler settings, keyed by `Inc::name`. Compilers not listed are enabled.
+  pub incs: HashMap<String, IncConfig>,
+
+  pub limits: Limits,
+  pub auth: AuthConfig,
+  pub connection: ConnectionConfig,
+  pub targets: TargetsConfig,
+
+  /// Serve wss:// instead of ws://
+  pub tls: Option<TlsConfig>,
+
+  /// Folder containing the webpack bundle (e.g., `dist`). Without it only the API is served.
+  pub static_dir: Option<PathBuf>,
+
+  /// Serve Prometheus metrics at `/metrics`
+  pub metrics: bool,
+
+  /// Seconds to wait for connections to close on shutdown before exiting anyway
+  pub shutdown_timeout: u64,
+
+  pub log_level: LevelFilter,
+  pub log_format: LogFormat
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      bind: SocketAddr::from(([127, 0, 0, 1], 8000)),
+      backing: BackingConfig::default(),
+      tmp_dir: std::env::temp_dir().join("ivygate"),
+      incs: HashMap::new(),
+      limits: Limits::default(),
+      auth: AuthConfig::default(),
+      connection: ConnectionConfig::default(),
+      targets: TargetsConfig::default(),
+      tls: None,
+      static_dir: None,
+      metrics: true,
+      shutdown_timeout: 10,
+      log_level: LevelFilter::Info,
+      log_format: LogFormat::default()
+    }
+  }
+}
+
+impl Config {
+  /// Reads the configuration file named in `args` (if any), then applies the
+  /// remaining arguments on top of it
+  pub fn load(args: Args) -> anyhow::Result<Self> {
+    let mut ret: Self = match &args.config {
+      Some(path) => {
+        let text = std::fs::read_to_string(path)
+          .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid configuration in {}", path.display()))?
+      },
+      None => Self::default()
+    };
+
+    if let Some(bind) = args.bind {
+      ret.bind = bind;
+    }
+
+    if let Some(root) = args.storage_root {
+      ret.backing = BackingConfig::Simple { root };
+    }
+
+    match (args.tls_cert, args.tls_key) {
+      (Some(cert), Some(key)) => ret.tls = Some(TlsConfig { cert, key }),
+      (None, None) => {},
+      _ => return Err(ConfigError::IncompleteTls.into())
+    }
+
+    if let Some(static_dir) = args.static_dir {
+      ret.static_dir = Some(static_dir);
+    }
+
+    if let Some(log_level) = args.log_level {
+      ret.log_level = log_level;
+    }
+
+    if let Some(log_format) = args.log_format {
+      ret.log_format = log_format;
+    }
+
+    ret.validate()?;
+    Ok(ret)
+  }
+
+  fn validate(&self) -> Result<(), ConfigError> {
+    for name in self.incs.keys() {
+      if !INCS.contains(&name.as_str()) {
+        return Err(ConfigError::UnknownInc(name.clone()));
+      }
+    }
+
+    if let Some(tls) = &self.tls {
+      for path in [&tls.cert, &tls.key].iter() {
+        if !path.is_file() {
+          return Err(ConfigError::Missing(path.to_path_buf()));
+        }
+      }
+    }
+
+    if let Some(static_dir) = &self.static_dir {
+      if !static_dir.join("index.html").is_file() {
+        return Err(ConfigError::Missing(static_dir.join("index.html")));
+      }
+    }
+
+    if let Some(sysroot) = &self.targets.wombat_sysroot {
+      if !sysroot.is_dir() {
+        return Err(ConfigError::Missing(sysroot.clone()));
+      }
+    }
+
+    if self.connection.max_bad_frames == 0 {
+      return Err(ConfigError::NoBadFrames);
+    }
+
+    if self.connection.ping_interval > 0 && self.connection.pong_timeout == 0 {
+      return Err(ConfigError::NoPongTimeout);
+    }
+
+    if self.limits.requests_per_second.is_nan() || self.limits.requests_per_second <= 0.0 || self.limits.request_burst == 0 {
+      return Err(ConfigError::NoRequests);
+    }
+
+    if let Some(secret) = &self.auth.secret {
+      let secret = secret.trim().to_lowercase();
+      if secret.is_empty() || PLACEHOLDER_SECRETS.contains(&secret.as_str()) {
+        return Err(ConfigError::PlaceholderSecret);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Settings for the incremental compiler called `name`
+  pub fn inc(&self, name: &str) -> IncConfig {
+    self.incs.get(name).cloned().unwrap_or_default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::path::Path;
+
+  /// Loads `toml` as the configuration file, with `args` on the command line
+  fn load(toml: &str, args: &[&str]) -> anyhow::Result<Config> {
+    let path = std::env::temp_dir().join(format!("ivygate-config-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(&path, toml).unwrap();
+
+    let mut argv = vec!["ivygate-server", "--config", path.to_str().unwrap()];
+    argv.extend_from_slice(args);
+    let ret = Config::load(Args::try_parse_from(argv).unwrap());
+
+    let _ = std::fs::remove_file(&path);
+    ret
+  }
+
+  fn config_error(result: anyhow::Result<Config>) -> ConfigError {
+    match result {
+      Ok(config) => panic!("accepted {:?}", config),
+      Err(e) => e.downcast::<ConfigError>().unwrap_or_else(|e| panic!("failed with {}", e))
+    }
+  }
+
+  #[test]
+  fn accepts_the_defaults() {
+    load("", &[]).unwrap();
+  }
+
+  #[test]
+  fn rejects_invalid_values() {
+    assert!(matches!(config_error(load("[incs.gcc]\nenabled = true", &[])), ConfigError::UnknownInc(name) if name == "gcc"));
+    assert!(matches!(config_error(load("[connection]\nmax_bad_frames = 0", &[])), ConfigError::NoBadFrames));
+    assert!(matches!(config_error(load("[connection]\npong_timeout = 0", &[])), ConfigError::NoPongTimeout));
+    assert!(matches!(config_error(load("[limits]\nrequests_per_second = 0.0", &[])), ConfigError::NoRequests));
+    assert!(matches!(config_error(load("[limits]\nrequest_burst = 0", &[])), ConfigError::NoRequests));
+    assert!(matches!(config_error(load("[targets]\nwombat_sysroot = \"/no/such/sysroot\"", &[])), ConfigError::Missing(_)));
+    assert!(matches!(config_error(load("static_dir = \"/no/such/dist\"", &[])), ConfigError::Missing(_)));
+
+    assert!(matches!(config_error(load("[auth]\nsecret = \"\"", &[])), ConfigError::PlaceholderSecret));
+    assert!(matches!(config_error(load("[auth]\nsecret = \" Change Me \"", &[])), ConfigError::PlaceholderSecret));
+    load("[auth]\nsecret = \"Kx8vQ2pLm4\"", &[]).unwrap();
+
+    // Without pings there's nothing to time out
+    load("[connection]\nping_interval = 0\npong_timeout = 0", &[]).unwrap();
+  }
+
+  #[test]
+  fn rejects_unknown_keys_and_malformed_values() {
+    assert!(load("[limits]\nmax_sesions = 4", &[]).is_err());
+    assert!(load("bind = \"localhost\"", &[]).is_err());
+    assert!(load("log_format = \"xml\"", &[]).is_err());
+    assert!(load("[backing]\nkind = \"s3\"", &[]).is_err());
+  }
+
+  #[test]
+  fn rejects_half_of_a_tls_pair() {
+    assert!(matches!(config_error(load("", &["--tls-cert", "cert.pem"])), ConfigError::IncompleteTls));
+    assert!(matches!(config_error(load("", &["--tls-key", "key.pem"])), ConfigError::IncompleteTls));
+
+    let missing = ["--tls-cert", "/no/such/cert.pem", "--tls-key", "/no/such/key.pem"];
+    assert!(matches!(config_error(load("", &missing)), ConfigError::Missing(_)));
+  }
+
+  #[test]
+  fn prefers_arguments_to_the_file() {
+    let toml = r#"
+      bind = "127.0.0.1:9000"
+      log_level = "warn"
+      log_format = "json"
+
+      [backing]
+      kind = "simple"
+      root = "from-file"
+    "#;
+
+    let config = load(toml, &[]).unwrap();
+    assert_eq!(config.bind, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(config.log_level, LevelFilter::Warn);
+
+    let args = ["--bind", "0.0.0.0:9001", "--storage-root", "from-args", "--log-level", "debug", "--log-format", "text"];
+    let config = load(toml, &args).unwrap();
+    assert_eq!(config.bind, "0.0.0.0:9001".parse().unwrap());
+    assert_eq!(config.log_level, LevelFilter::Debug);
+    assert_eq!(config.log_format, LogFormat::Text);
+    assert!(matches!(config.backing, BackingConfig::Simple { root } if root == Path::new("from-args")));
+
+    // Anything not given on the command line still comes from the file
+    let config = load(toml, &["--log-level", "debug"]).unwrap();
+    assert_eq!(config.bind, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert!(matches!(config.backing, BackingConfig::Simple { root } if root == Path::new("from-file")));
+  }
+}
@@ -0,0 +1,989 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use lazy_static::lazy_static;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+use crate::backing::{Backing, UserBacking, Project, WatchGuard};
+use crate::fs::{self, Contents, EntryInfo};
+use crate::inc::{Session, SpawnError, Message, Target};
+use crate::metrics;
+use crate::path::ProjectPath;
+use crate::proto::*;
+use crate::quota::{self, Permit, RateLimiter};
+use crate::shutdown::Shutdown;
+use crate::token::{self, Claims};
+use crate::{config, Notifier, INC_SPAWNER};
+
+lazy_static! {
+  /// State left behind by disconnected connections, keyed by the `jti` of their session token
+  static ref PARKED: std::sync::Mutex<HashMap<Uuid, Parked>> = std::sync::Mutex::new(HashMap::new());
+}
+
+/// Distinguishes successive parkings under the same token
+static PARK_ITER: AtomicU64 = AtomicU64::new(0);
+
+/// How long after this connection writes a file the watcher's report of it is ignored
+const OWN_WRITE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Describes what this server supports to a client during the handshake
+fn capabilities() -> Capabilities {
+  let mut ret = Capabilities::default();
+
+  for inc in INC_SPAWNER.incs() {
+    ret.incs.push(inc.name().to_string());
+    ret.languages.extend(inc.languages().iter().map(|l| l.to_string()));
+  }
+
+  ret.features = FEATURES.iter().map(|f| f.to_string()).collect();
+  ret
+}
+
+type Shared<T> = Arc<Mutex<T>>;
+
+/// A file opened by the client, referred to by its handle
+struct OpenFile {
+  project: Uuid,
+  path: ProjectPath,
+
+  /// The project's target when the file was opened, which its session compiles for
+  target: Target,
+
+  /// `None` if no incremental compiler supports the file
+  session: Option<Box<dyn Session>>,
+
+  /// The latest contents sent by the client, or read when the file was opened
+  contents: Contents,
+
+  /// Incremented whenever the client sends new contents
+  version: u64,
+
+  /// Counts `session` against the user's quota
+  _permit: Option<Permit>
+}
+
+/// The open projects and files of a connection that dropped, kept for
+/// `connection.reattach_grace` seconds in case the client resumes
+struct Parked {
+  generation: u64,
+  user: Box<dyn UserBacking>,
+  projects: HashMap<Uuid, Shared<Box<dyn Project>>>,
+  files: HashMap<u64, Shared<OpenFile>>,
+  sizes: HashMap<Uuid, HashMap<PathBuf, u64>>,
+  handle_iter: u64
+}
+
+impl Parked {
+  async fn close(mut self) {
+    drop(self.files);
+    close_projects(Some(&mut self.user), self.projects.into_iter().collect()).await;
+  }
+}
+
+/// Flushes `projects` and tells the user's backing they're closed
+async fn close_projects(user: Option<&mut Box<dyn UserBacking>>, projects: Vec<(Uuid, Shared<Box<dyn Project>>)>) {
+  for (uuid, project) in projects.iter() {
+    if let Err(e) = project.lock().await.flush().await {
+      warn!("Failed to flush project {}: {}", uuid, e);
+    }
+  }
+
+  if let Some(user) = user {
+    for (uuid, _) in projects {
+      let _ = user.close_project(uuid).await;
+    }
+  }
+}
+
+/// State belonging to a single client connection.
+/// Requests only need `&self`, so independent requests can be serviced concurrently.
+pub struct Connection {
+  backing: Arc<dyn Backing>,
+  user: Mutex<Option<Box<dyn UserBacking>>>,
+  projects: Mutex<HashMap<Uuid, Shared<Box<dyn Project>>>>,
+  handle_iter: AtomicU64,
+  files: Mutex<HashMap<u64, Shared<OpenFile>>>,
+  notifier: Notifier,
+
+  /// The logged-in user's `Ident`, which keys their per-user quotas
+  ident: Mutex<Option<String>>,
+
+  /// Claims of the session token issued at login or presented to resume
+  claims: Mutex<Option<Claims>>,
+
+  /// Size of every file in each open project, to enforce `limits.max_project_size`
+  sizes: Mutex<HashMap<Uuid, HashMap<PathBuf, u64>>>,
+
+  /// Watches the open projects for changes made by anyone else
+  watches: Mutex<HashMap<Uuid, WatchGuard>>,
+  changes: UnboundedSender<FileChangedNotification>,
+  changed: Mutex<UnboundedReceiver<FileChangedNotification>>,
+
+  /// When this connection last wrote each file, so the watchers don't echo it back
+  own_writes: std::sync::Mutex<HashMap<(Uuid, PathBuf), Instant>>,
+
+  /// Limits requests before login, after which the user's shared rate applies
+  rate: std::sync::Mutex<RateLimiter>,
+  user_rate: std::sync::Mutex<Option<Arc<std::sync::Mutex<RateLimiter>>>>,
+
+  /// The connection's span, which records the user once they log in
+  span: Span
+}
+
+impl Connection {
+  pub fn new(backing: Arc<dyn Backing>, notifier: Notifier) -> Self {
+    let (changes, changed) = unbounded_channel();
+
+    Self {
+      backing,
+      user: Mutex::new(None),
+      projects: Mutex::new(HashMap::new()),
+      handle_iter: AtomicU64::new(0),
+      files: Mutex::new(HashMap::new()),
+      notifier,
+      ident: Mutex::new(None),
+      claims: Mutex::new(None),
+      sizes: Mutex::new(HashMap::new()),
+      watches: Mutex::new(HashMap::new()),
+      changes,
+      changed: Mutex::new(changed),
+      own_writes: std::sync::Mutex::new(HashMap::new()),
+      rate: std::sync::Mutex::new(RateLimiter::default()),
+      user_rate: std::sync::Mutex::new(None),
+      span: Span::current()
+    }
+  }
+
+  async fn project(&self, uuid: Uuid) -> Result<Shared<Box<dyn Project>>, Error> {
+    self.projects.lock().await.get(&uuid).cloned().ok_or_else(|| Error::project_not_open(uuid))
+  }
+
+  async fn file(&self, handle: u64) -> Result<Shared<OpenFile>, Error> {
+    self.files.lock().await.get(&handle).cloned().ok_or_else(|| Error::no_such_handle(handle))
+  }
+
+  pub async fn logged_in(&self) -> bool {
+    self.ident.lock().await.is_some()
+  }
+
+  async fn ident(&self) -> Result<String, Error> {
+    self.ident.lock().await.clone().ok_or_else(Error::not_logged_in)
+  }
+
+  /// Fails if writing `len` bytes to `path` would exceed the file or project size limits
+  async fn check_write(&self, project: Uuid, path: &Path, len: u64) -> Result<(), Error> {
+    quota::check_file_size(path, len)?;
+
+    if let Some(sizes) = self.sizes.lock().await.get(&project) {
+      let others: u64 = sizes.iter().filter(|(p, _)| p.as_path() != path).map(|(_, size)| size).sum();
+      quota::check_project_size(others + len)?;
+    }
+
+    Ok(())
+  }
+
+  /// Records the size of `path` after a write, or its removal if `len` is `None`
+  async fn record_size(&self, project: Uuid, path: &Path, len: Option<u64>) {
+    if let Some(sizes) = self.sizes.lock().await.get_mut(&project) {
+      match len {
+        Some(len) => {
+          sizes.insert(path.to_path_buf(), len);
+        },
+        // Anything inside a removed folder went with it
+        None => sizes.retain(|p, _| !p.starts_with(path))
+      }
+    }
+  }
+
+  /// Like `record_size`, for writes this connection made itself
+  async fn record_write(&self, project: Uuid, path: &Path, len: Option<u64>) {
+    self.record_size(project, path, len).await;
+
+    let now = Instant::now();
+    let mut own_writes = self.own_writes.lock().unwrap();
+    own_writes.retain(|_, at| now.duration_since(*at) < OWN_WRITE_WINDOW);
+    own_writes.insert((project, path.to_path_buf()), now);
+  }
+
+  /// Whether this connection wrote `path` recently enough to have caused a change to it
+  fn own_write(&self, project: Uuid, path: &Path) -> bool {
+    match self.own_writes.lock().unwrap().get(&(project, path.to_path_buf())) {
+      Some(at) => at.elapsed() < OWN_WRITE_WINDOW,
+      None => false
+    }
+  }
+
+  /// Starts reporting changes to `project` on disk, if its backing can
+  async fn watch(&self, uuid: Uuid, project: &dyn Project) {
+    match project.watch(self.changes.clone()).await {
+      Ok(Some(guard)) => {
+        self.watches.lock().await.insert(uuid, guard);
+      },
+      Ok(None) => {},
+      // Clients just won't hear about changes made elsewhere
+      Err(e) => warn!("Failed to watch project {}: {}", uuid, e)
+    }
+  }
+
+  /// Number of open files that are backed by a compiler session
+  async fn session_count(&self) -> usize {
+    let files: Vec<_> = self.files.lock().await.values().cloned().collect();
+
+    let mut ret = 0;
+    for file in files {
+      if file.lock().await.session.is_some() {
+        ret += 1;
+      }
+    }
+    ret
+  }
+
+  /// Replaces the connection's user with the one `claims` were issued to
+  async fn set_user(&self, user: Box<dyn UserBacking>, claims: Claims) {
+    // Nothing the previous user opened may survive
+    self.close_all().await;
+    *self.user.lock().await = Some(user);
+    *self.ident.lock().await = Some(claims.ident.to_string());
+    *self.user_rate.lock().unwrap() = Some(quota::user_rate(&claims.ident.to_string()));
+
+    self.span.record("user", tracing::field::display(&claims.ident));
+    *self.claims.lock().await = Some(claims);
+  }
+
+  /// Returns a session token for resuming later and its expiry
+  async fn login(&self, LoginReq { user }: &LoginReq) -> Result<(String, u64), Error> {
+    let backing = self.backing.login(user.clone()).await?;
+    let (token, claims) = token::issue(user.ident.clone())?;
+    let expires = claims.exp;
+
+    self.set_user(backing, claims).await;
+    info!("Logged in");
+    Ok((token, expires))
+  }
+
+  /// Returns the files reattached from a previous connection using the same token
+  async fn resume(&self, ResumeReq { token }: &ResumeReq) -> Result<Vec<ReattachedFile>, Error> {
+    let claims = token::verify(token).map_err(|e| Error::new(ErrorCode::AuthFailed, e.to_string()))?;
+
+    let parked = PARKED.lock().unwrap().remove(&claims.jti);
+    let parked = match parked {
+      Some(parked) => parked,
+      None => {
+        let user = self.backing.resume(claims.ident.clone()).await?;
+        self.set_user(user, claims).await;
+        info!("Resumed session");
+        return Ok(Vec::new());
+      }
+    };
+
+    let Parked { user, projects, files, sizes, handle_iter, .. } = parked;
+    self.set_user(user, claims).await;
+
+    let mut ret = Vec::with_capacity(files.len());
+    for (handle, file) in files.iter() {
+      let file = file.lock().await;
+      ret.push(ReattachedFile {
+        handle: *handle,
+        project: file.project,
+        path: file.path.clone(),
+        version: file.version,
+        contents: file.contents.clone()
+      });
+    }
+    ret.sort_by_key(|file| file.handle);
+
+    for (uuid, project) in projects.iter() {
+      self.watch(*uuid, &**project.lock().await).await;
+    }
+
+    *self.projects.lock().await = projects;
+    *self.files.lock().await = files;
+    *self.sizes.lock().await = sizes;
+    self.handle_iter.store(handle_iter, Ordering::SeqCst);
+
+    info!(files = ret.len(), "Resumed session and reattached open files");
+    Ok(ret)
+  }
+
+  async fn logout(&self) -> Result<(), Error> {
+    let claims = self.claims.lock().await.take().ok_or_else(Error::not_logged_in)?;
+    token::revoke(&claims);
+
+    self.close_all().await;
+    *self.ident.lock().await = None;
+    *self.user_rate.lock().unwrap() = None;
+    if let Some(mut user) = self.user.lock().await.take() {
+      user.logout().await?;
+    }
+
+    info!("Logged out");
+    Ok(())
+  }
+
+  async fn list_projects(&self) -> Result<Vec<ProjectBrief>, Error> {
+    let user = self.user.lock().await;
+    let user = user.as_ref().ok_or_else(Error::not_logged_in)?;
+    Ok(user.projects().await?)
+  }
+
+  async fn create_project(&self, CreateProjectReq { name }: &CreateProjectReq) -> Result<ProjectBrief, Error> {
+    let mut user = self.user.lock().await;
+    let user = user.as_mut().ok_or_else(Error::not_logged_in)?;
+    Ok(user.create_project(name.clone()).await?)
+  }
+
+  async fn delete_project(&self, DeleteProjectReq { uuid }: &DeleteProjectReq) -> Result<(), Error> {
+    if self.projects.lock().await.contains_key(uuid) {
+      self.close_project(&CloseProjectReq { uuid: *uuid }).await?;
+    }
+
+    let mut user = self.user.lock().await;
+    let user = user.as_mut().ok_or_else(Error::not_logged_in)?;
+    Ok(user.delete_project(*uuid).await?)
+  }
+
+  async fn open_project(&self, OpenProjectReq { uuid }: &OpenProjectReq) -> Result<(), Error> {
+    let mut user = self.user.lock().await;
+    let user = user.as_mut().ok_or_else(Error::not_logged_in)?;
+
+    let mut projects = self.projects.lock().await;
+    if !projects.contains_key(uuid) {
+      let project = user.open_project(*uuid).await?;
+
+      // Only used to enforce quotas, so an unreadable project is still usable
+      let sizes = match project.list(None, None).await {
+        Ok(entries) => fs::file_sizes(&entries).into_iter().collect(),
+        Err(e) => {
+          warn!("Failed to measure project {}: {}", uuid, e);
+          HashMap::new()
+        }
+      };
+      self.sizes.lock().await.insert(*uuid, sizes);
+
+      self.watch(*uuid, &*project).await;
+      projects.insert(*uuid, Arc::new(Mutex::new(project)));
+    }
+
+    Ok(())
+  }
+
+  async fn close_project(&self, CloseProjectReq { uuid }: &CloseProjectReq) -> Result<(), Error> {
+    let project = self.projects.lock().await.remove(uuid).ok_or_else(|| Error::project_not_open(*uuid))?;
+    self.sizes.lock().await.remove(uuid);
+    self.watches.lock().await.remove(uuid);
+    project.lock().await.flush().await?;
+
+    // Handles into the project are meaningless without it
+    let mut files = self.files.lock().await;
+    let mut closed = Vec::new();
+    for (handle, file) in files.iter() {
+      if file.lock().await.project == *uuid {
+        closed.push(*handle);
+      }
+    }
+    for handle in closed {
+      files.remove(&handle);
+    }
+    drop(files);
+
+    let mut user = self.user.lock().await;
+    let user = user.as_mut().ok_or_else(Error::not_logged_in)?;
+    Ok(user.close_project(*uuid).await?)
+  }
+
+  /// Closes every open file and project, e.g., when the connection ends
+  pub async fn close_all(&self) {
+    self.files.lock().await.clear();
+    self.sizes.lock().await.clear();
+    self.watches.lock().await.clear();
+
+    let projects: Vec<_> = self.projects.lock().await.drain().collect();
+    close_projects(self.user.lock().await.as_mut(), projects).await;
+  }
+
+  /// Called when the client disconnects. A logged-in user's open files and projects
+  /// are kept for `connection.reattach_grace` seconds so a `ResumeReq` presenting the
+  /// same token can pick them up again; everything else is closed immediately.
+  pub async fn park(&self, mut shutdown: Shutdown) {
+    let grace = config::get().connection.reattach_grace;
+    let claims = self.claims.lock().await.take();
+    let user = self.user.lock().await.take();
+
+    let (claims, user) = match (claims, user) {
+      (Some(claims), Some(user)) if grace > 0 => (claims, user),
+      (_, user) => {
+        *self.user.lock().await = user;
+        return self.close_all().await;
+      }
+    };
+
+    // Nobody would hear about changes while parked. Resuming watches again.
+    self.watches.lock().await.clear();
+
+    let generation = PARK_ITER.fetch_add(1, Ordering::SeqCst);
+    let parked = Parked {
+      generation,
+      user,
+      projects: self.projects.lock().await.drain().collect(),
+      files: self.files.lock().await.drain().collect(),
+      sizes: self.sizes.lock().await.drain().collect(),
+      handle_iter: self.handle_iter.load(Ordering::SeqCst)
+    };
+
+    debug!(files = parked.files.len(), "Parking open files for {}s", grace);
+    let replaced = PARKED.lock().unwrap().insert(claims.jti, parked);
+
+    // Only one connection at a time can be resumed from a token
+    if let Some(replaced) = replaced {
+      replaced.close().await;
+    }
+
+    tokio::spawn(async move {
+      tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(grace)) => {},
+        // Closing flushes, so don't leave it to the end of the grace period
+        _ = shutdown.wait() => {}
+      }
+
+      let expired = {
+        let mut parked = PARKED.lock().unwrap();
+        match parked.get(&claims.jti) {
+          Some(p) if p.generation == generation => parked.remove(&claims.jti),
+          _ => None
+        }
+      };
+
+      if let Some(expired) = expired {
+        debug!("Closing parked files that weren't reattached");
+        expired.close().await;
+      }
+    }.in_current_span());
+  }
+
+  async fn list_files(&self, ListFilesReq { project: uuid, path, depth }: &ListFilesReq) -> Result<Vec<EntryInfo>, Error> {
+    let project = self.project(*uuid).await?;
+    let project = project.lock().await;
+
+    project.list(path.clone(), *depth).await.map_err(|e| match path {
+      Some(path) => Error::from(e).with_path(path),
+      None => Error::from(e)
+    })
+  }
+
+  async fn create_file(&self, CreateFileReq { project: uuid, path, contents }: &CreateFileReq) -> Result<(), Error> {
+    let project = self.project(*uuid).await?;
+    let mut project = project.lock().await;
+
+    let contents = contents.clone().unwrap_or_default();
+    let len = contents.len() as u64;
+    self.check_write(*uuid, path, len).await?;
+
+    project.save(path.clone(), contents).await
+      .map_err(|e| Error::from(e).with_path(path))?;
+    self.record_write(*uuid, path, Some(len)).await;
+    Ok(())
+  }
+
+  async fn delete_file(&self, DeleteFileReq { project: uuid, path }: &DeleteFileReq) -> Result<Contents, Error> {
+    let project = self.project(*uuid).await?;
+    let mut project = project.lock().await;
+
+    let contents = project.read(path.clone()).await.map_err(|e| Error::from(e).with_path(path))?;
+    project.delete(path.clone()).await.map_err(|e| Error::from(e).with_path(path))?;
+    self.record_write(*uuid, path, None).await;
+    Ok(contents)
+  }
+
+  /// Returns the new handle, the file's contents and version, and its MIME type
+  async fn open_file(&self, OpenFileReq { project: uuid, path }: &OpenFileReq) -> Result<(u64, Contents, u64, &'static str), Error> {
+    // Checked again once the handle is added, but there's no point reading the file first
+    quota::check_handles(self.files.lock().await.len()).map_err(|e| e.with_path(path))?;
+
+    let (contents, target) = {
+      let project = self.project(*uuid).await?;
+      let project = project.lock().await;
+
+      let contents = project.read(path.clone()).await.map_err(|e| Error::from(e).with_path(path))?;
+      (contents, project.target().await?)
+    };
+
+    // Binary files are never compiled
+    let started = match contents.as_text() {
+      Some(text) => self.start_session(*uuid, path, target.clone(), text).await?,
+      None => None
+    };
+    let (session, permit) = match started {
+      Some((session, permit)) => (Some(session), Some(permit)),
+      None => (None, None)
+    };
+
+    let mut files = self.files.lock().await;
+    quota::check_handles(files.len()).map_err(|e| e.with_path(path))?;
+
+    let handle = self.handle_iter.fetch_add(1, Ordering::SeqCst) + 1;
+    let mime = fs::mime_type(path, contents.encoding());
+    files.insert(handle, Arc::new(Mutex::new(OpenFile {
+      project: *uuid,
+      path: path.clone(),
+      target,
+      session,
+      contents: contents.clone(),
+      version: 0,
+      _permit: permit
+    })));
+
+    Ok((handle, contents, 0, mime))
+  }
+
+  /// Spawns a compiler session for a file being opened, unless no incremental compiler supports it
+  async fn start_session(&self, uuid: Uuid, path: &Path, target: Target, text: &str) -> Result<Option<(Box<dyn Session>, Permit)>, Error> {
+    let ident = self.ident().await?;
+    let max_sessions = crate::config::get().limits.max_sessions;
+    if self.session_count().await >= max_sessions {
+      let message = format!("At most {} compiler sessions are allowed per connection", max_sessions);
+      return Err(Error::quota_exceeded(message).with_path(path));
+    }
+    let permit = quota::session(&ident).map_err(|e| e.with_path(path))?;
+
+    // Sessions are keyed by project so files with the same path in different projects
+    // don't collide. The contents come from the backing, not the session's path.
+    let session_path = PathBuf::from(uuid.to_string()).join(path);
+    match INC_SPAWNER.spawn(&session_path, target).await {
+      Ok(mut session) => {
+        let _build = quota::build(&ident).map_err(|e| e.with_path(path))?;
+        session.update(Some(text.to_string())).await?;
+        Ok(Some((session, permit)))
+      },
+      Err(e) => match e.downcast_ref::<SpawnError>() {
+        Some(SpawnError::NoInc) => Ok(None),
+        None => Err(Error::from(e).with_path(path))
+      }
+    }
+  }
+
+  async fn update_file(&self, UpdateFileReq { handle, code }: &UpdateFileReq) -> Result<(Vec<Message>, Target, u64), Error> {
+    let file = self.file(*handle).await?;
+    let mut file = file.lock().await;
+
+    // Taken before saving, so a rejected update changes nothing
+    let _build = match file.session {
+      Some(_) => Some(quota::build(&self.ident().await?).map_err(|e| e.with_handle(*handle))?),
+      None => None
+    };
+
+    if let Some(code) = code {
+      let project = self.project(file.project).await?;
+      let mut project = project.lock().await;
+
+      let len = code.len() as u64;
+      self.check_write(file.project, &file.path, len).await?;
+
+      project.save(file.path.clone(), code.clone().into()).await
+        .map_err(|e| Error::from(e).with_path(&file.path))?;
+      self.record_write(file.project, &file.path, Some(len)).await;
+
+      file.contents = code.clone().into();
+      file.version += 1;
+    }
+
+    let version = file.version;
+    let target = file.target.clone();
+    let session = match file.session.as_mut() {
+      Some(session) => session,
+      None => return Ok((Vec::new(), target, version))
+    };
+
+    let e = match session.update(code.clone()).await {
+      Ok(messages) => return Ok((messages, target, version)),
+      Err(e) => e
+    };
+
+    if !session.alive() {
+      // The handle table is always locked before a file, never after
+      drop(file);
+      self.files.lock().await.remove(handle);
+      let _ = self.notifier.send(SessionDiedNotification {
+        handle: *handle,
+        reason: e.to_string()
+      }.into());
+    }
+
+    Err(Error::from(e).with_handle(*handle))
+  }
+
+  async fn close_file(&self, CloseFileReq { handle }: &CloseFileReq) -> Result<(), Error> {
+    match self.files.lock().await.remove(handle) {
+      Some(_) => Ok(()),
+      None => Err(Error::no_such_handle(*handle))
+    }
+  }
+
+  /// Waits for the next change the open projects' watchers notice
+  pub async fn next_change(&self) -> Option<FileChangedNotification> {
+    self.changed.lock().await.recv().await
+  }
+
+  /// Tells the client about a change made to an open project by someone else, and
+  /// brings handles open on the file up to date, refreshing their diagnostics
+  pub async fn file_changed(&self, FileChangedNotification { project: uuid, path, change }: FileChangedNotification) {
+    // The project may have been closed since
+    let project = match self.projects.lock().await.get(&uuid).cloned() {
+      Some(project) => project,
+      None => return
+    };
+
+    if self.own_write(uuid, &path) {
+      return;
+    }
+
+    let contents = match change {
+      FileChange::Deleted => None,
+      // Folders can't be read, and neither can files that are already gone again
+      FileChange::Created | FileChange::Modified => project.lock().await.read(path.clone()).await.ok()
+    };
+
+    match (change, &contents) {
+      (FileChange::Deleted, _) => self.record_size(uuid, &path, None).await,
+      (_, Some(contents)) => self.record_size(uuid, &path, Some(contents.len() as u64)).await,
+      _ => {}
+    }
+
+    debug!(project = %uuid, path = %path, ?change, "File changed on disk");
+    let _ = self.notifier.send(FileChangedNotification {
+      project: uuid,
+      path: path.clone(),
+      change
+    }.into());
+
+    let contents = match contents {
+      Some(contents) => contents,
+      None => return
+    };
+
+    let files: Vec<_> = self.files.lock().await.iter().map(|(handle, file)| (*handle, file.clone())).collect();
+    for (handle, file) in files {
+      let mut file = file.lock().await;
+      if file.project != uuid || file.path != path || file.contents == contents {
+        continue;
+      }
+
+      file.contents = contents.clone();
+      file.version += 1;
+
+      let text = match contents.as_text() {
+        Some(text) => text.to_string(),
+        None => continue
+      };
+      let session = match file.session.as_mut() {
+        Some(session) => session,
+        None => continue
+      };
+
+      let ident = match self.ident().await {
+        Ok(ident) => ident,
+        Err(_) => return
+      };
+      // Stale diagnostics aren't worth exceeding the quota over
+      let _build = match quota::build(&ident) {
+        Ok(build) => build,
+        Err(e) => {
+          debug!("Not refreshing handle {}: {}", handle, e);
+          continue;
+        }
+      };
+
+      let e = match session.update(Some(text)).await {
+        Ok(messages) => {
+          let _ = self.notifier.send(DiagnosticsNotification {
+            handle,
+            messages,
+            target: session.target().clone()
+          }.into());
+          continue;
+        },
+        Err(e) => e
+      };
+
+      if session.alive() {
+        warn!("Failed to refresh handle {}: {}", handle, e);
+        continue;
+      }
+
+      // The handle table is always locked before a file, never after
+      drop(file);
+      self.files.lock().await.remove(&handle);
+      let _ = self.notifier.send(SessionDiedNotification {
+        handle,
+        reason: e.to_string()
+      }.into());
+    }
+  }
+
+  /// Services a single request
+  pub async fn dispatch(&self, req: &Req) -> Res {
+    let span = info_span!("req", id = req.id, kind = req.kind.name());
+
+    async {
+      // Bound first so the lock isn't held while the request is serviced
+      let user_rate = self.user_rate.lock().unwrap().clone();
+      let allowed = match user_rate {
+        Some(rate) => rate.lock().unwrap().check(),
+        None => self.rate.lock().unwrap().check()
+      };
+      let res = match allowed {
+        Ok(()) => self.handle(req).await,
+        Err(e) => req.reply(req.kind.reject(e))
+      };
+      metrics::request(&req.kind, &res.kind);
+
+      match res.kind.error() {
+        Some(e) if e.code == ErrorCode::Internal => warn!("{}", e.message),
+        Some(e) => debug!(code = e.code.name(), "{}", e.message),
+        None => {}
+      }
+
+      res
+    }.instrument(span).await
+  }
+
+  async fn handle(&self, req: &Req) -> Res {
+    match &req.kind {
+      ReqKind::Hello(HelloReq { version, encodings }) => {
+        if !compatible(*version) {
+          return req.reply(HelloRes::error(Error::new(
+            ErrorCode::IncompatibleVersion,
+            format!(
+              "Incompatible protocol version {} (server supports {} through {})",
+              version,
+              MIN_PROTOCOL_VERSION,
+              PROTOCOL_VERSION
+            )
+          )));
+        }
+
+        req.reply(HelloRes::success(capabilities(), Encoding::negotiate(encodings)))
+      },
+      ReqKind::LoginReq(login) => req.reply(match self.login(login).await {
+        Ok((token, expires)) => LoginRes::success(token, expires),
+        Err(e) => LoginRes::error(e)
+      }),
+      ReqKind::Resume(resume) => req.reply(match self.resume(resume).await {
+        Ok(files) => ResumeRes::success(files),
+        Err(e) => ResumeRes::error(e)
+      }),
+      ReqKind::Logout(_) => req.reply(match self.logout().await {
+        Ok(()) => LogoutRes::success(),
+        Err(e) => LogoutRes::error(e)
+      }),
+      ReqKind::ListProjectsReq(_) => req.reply(match self.list_projects().await {
+        Ok(projects) => ListProjectsRes::success(projects),
+        Err(e) => ListProjectsRes::error(e)
+      }),
+      ReqKind::CreateProject(create) => req.reply(match self.create_project(create).await {
+        Ok(project) => CreateProjectRes::success(project),
+        Err(e) => CreateProjectRes::error(e)
+      }),
+      ReqKind::DeleteProject(delete) => req.reply(match self.delete_project(delete).await {
+        Ok(()) => DeleteProjectRes::success(),
+        Err(e) => DeleteProjectRes::error(e)
+      }),
+      ReqKind::OpenProject(open) => req.reply(match self.open_project(open).await {
+        Ok(()) => OpenProjectRes::success(),
+        Err(e) => OpenProjectRes::error(e)
+      }),
+      ReqKind::CloseProject(close) => req.reply(match self.close_project(close).await {
+        Ok(()) => CloseProjectRes::success(),
+        Err(e) => CloseProjectRes::error(e)
+      }),
+      ReqKind::ListFiles(list) => req.reply(match self.list_files(list).await {
+        Ok(entries) => ListFilesRes::success(entries),
+        Err(e) => ListFilesRes::error(e)
+      }),
+      ReqKind::CreateFile(create) => req.reply(match self.create_file(create).await {
+        Ok(()) => CreateFileRes::success(),
+        Err(e) => CreateFileRes::error(e)
+      }),
+      ReqKind::DeleteFile(delete) => req.reply(match self.delete_file(delete).await {
+        Ok(contents) => DeleteFileRes::success(contents),
+        Err(e) => DeleteFileRes::error(e)
+      }),
+      ReqKind::OpenFile(open) => req.reply(match self.open_file(open).await {
+        Ok((handle, contents, version, mime)) => OpenFileRes::success(handle, contents, version, mime),
+        Err(e) => OpenFileRes::error(e)
+      }),
+      ReqKind::UpdateFile(update) => req.reply(match self.update_file(update).await {
+        Ok((messages, target, version)) => UpdateFileRes::success(messages, target, version),
+        Err(e) => UpdateFileRes::error(e)
+      }),
+      ReqKind::CloseFile(close) => req.reply(match self.close_file(close).await {
+        Ok(()) => CloseFileRes::success(),
+        Err(e) => CloseFileRes::error(e)
+      })
+    }
+  }
+
+  /// Services a batch of requests, replying in the same order they were given
+  pub async fn dispatch_batch(&self, batch: &Batch) -> Vec<Res> {
+    if batch.independent {
+      return join_all(batch.reqs.iter().map(|req| self.dispatch(req))).await;
+    }
+
+    let mut ret = Vec::with_capacity(batch.reqs.len());
+    for req in batch.reqs.iter() {
+      ret.push(self.dispatch(req).await);
+    }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use tokio::time::advance;
+
+  use crate::backing::SimpleBacking;
+  use crate::shutdown::Coordinator;
+
+  /// A scratch storage root for a simple backing, removed when dropped
+  struct Scratch(PathBuf);
+
+  impl Scratch {
+    fn new() -> Self {
+      config::init(config::Config::default());
+
+      let path = std::env::temp_dir().join(format!("ivygate-conn-{}", Uuid::new_v4()));
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+    }
+
+    fn connect(&self) -> Connection {
+      let (notifier, _) = unbounded_channel();
+      Connection::new(Arc::new(SimpleBacking::new(self.0.clone())), notifier)
+    }
+  }
+
+  impl Drop for Scratch {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  /// Logs in as a user of its own, since quotas are shared, and opens a new project.
+  /// Returns the session token and the project.
+  async fn open_project(conn: &Connection) -> (String, Uuid) {
+    let user = User {
+      ident: Ident::username(Uuid::new_v4().to_string()),
+      password: String::new()
+    };
+    let (token, _) = conn.login(&LoginReq { user }).await.unwrap();
+
+    let uuid = conn.create_project(&CreateProjectReq { name: "test".into() }).await.unwrap().uuid;
+    conn.open_project(&OpenProjectReq { uuid }).await.unwrap();
+    (token, uuid)
+  }
+
+  /// Opens a binary file, so it's never compiled, and returns its handle
+  async fn open_data(conn: &Connection, project: Uuid) -> u64 {
+    let path = ProjectPath::new("data.bin").unwrap();
+    let contents = Some(Contents::Binary(vec![0; 16]));
+    conn.create_file(&CreateFileReq { project, path: path.clone(), contents }).await.unwrap();
+    conn.open_file(&OpenFileReq { project, path }).await.unwrap().0
+  }
+
+  #[tokio::test]
+  async fn limits_open_files_without_sessions() {
+    let scratch = Scratch::new();
+    let conn = scratch.connect();
+    let (_, project) = open_project(&conn).await;
+
+    // Binary, so it's never compiled
+    let path = ProjectPath::new("data.bin").unwrap();
+    let contents = Some(Contents::Binary(vec![0; 16]));
+    conn.create_file(&CreateFileReq { project, path: path.clone(), contents }).await.unwrap();
+
+    let open = OpenFileReq { project, path };
+    let mut handles = Vec::new();
+    for _ in 0..config::get().limits.max_handles {
+      handles.push(conn.open_file(&open).await.unwrap().0);
+    }
+
+    match conn.open_file(&open).await {
+      Err(Error { code: ErrorCode::QuotaExceeded, .. }) => {},
+      result => panic!("{:?}", result)
+    }
+
+    // Closing one makes room for another
+    conn.close_file(&CloseFileReq { handle: handles[0] }).await.unwrap();
+    conn.open_file(&open).await.unwrap();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn reattaches_within_the_grace_period() {
+    let scratch = Scratch::new();
+    let coordinator = Coordinator::new();
+
+    let conn = scratch.connect();
+    let (token, project) = open_project(&conn).await;
+    let handle = open_data(&conn, project).await;
+    conn.park(coordinator.subscribe()).await;
+
+    advance(Duration::from_secs(config::get().connection.reattach_grace - 1)).await;
+
+    let conn = scratch.connect();
+    let reattached = conn.resume(&ResumeReq { token }).await.unwrap();
+    assert_eq!(reattached.len(), 1);
+    assert_eq!(reattached[0].handle, handle);
+    assert_eq!(reattached[0].project, project);
+    assert_eq!(reattached[0].contents, Contents::Binary(vec![0; 16]));
+
+    // The handle works, and new ones don't collide with it
+    conn.file(handle).await.unwrap();
+    let open = OpenFileReq { project, path: ProjectPath::new("data.bin").unwrap() };
+    assert_ne!(conn.open_file(&open).await.unwrap().0, handle);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn closes_files_after_the_grace_period() {
+    let scratch = Scratch::new();
+    let coordinator = Coordinator::new();
+
+    let conn = scratch.connect();
+    let (token, project) = open_project(&conn).await;
+    let handle = open_data(&conn, project).await;
+    conn.park(coordinator.subscribe()).await;
+
+    advance(Duration::from_secs(config::get().connection.reattach_grace + 1)).await;
+
+    // The token still logs in, but there's nothing left to reattach
+    let conn = scratch.connect();
+    assert!(conn.resume(&ResumeReq { token }).await.unwrap().is_empty());
+    assert!(matches!(conn.file(handle).await, Err(Error { code: ErrorCode::NoSuchHandle, .. })));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn revoked_tokens_cannot_reattach() {
+    let scratch = Scratch::new();
+    let coordinator = Coordinator::new();
+
+    let conn = scratch.connect();
+    let (token, project) = open_project(&conn).await;
+    open_data(&conn, project).await;
+    conn.park(coordinator.subscribe()).await;
+
+    token::revoke(&token::verify(&token).unwrap());
+
+    let conn = scratch.connect();
+    assert!(matches!(conn.resume(&ResumeReq { token }).await, Err(Error { code: ErrorCode::AuthFailed, .. })));
+    assert!(!conn.logged_in().await);
+  }
+}
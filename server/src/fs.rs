@@ -1,28 +1,218 @@
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, MapAccess, Visitor};
+use serde::ser::SerializeMap;
 use std::collections::HashMap;
+use std::fmt;
 
 use std::path::{Path, PathBuf};
 
 use std::future::Future;
 use std::pin::Pin;
+use std::time::UNIX_EPOCH;
 
 use derive_more::*;
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+lazy_static! {
+  /// This process's own folder under `tmp_dir`, which may be shared with anything else
+  static ref TMP_DIR: PathBuf = crate::config::get().tmp_dir.join(format!("server-{}", Uuid::new_v4()));
+}
 
 #[derive(Display, Debug, Error)]
 pub enum ReadError {
   UnsupportedEntry
 }
 
+/// How a file's bytes are interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileEncoding {
+  Utf8,
+  Binary
+}
+
+/// The contents of a file in a project.
+///
+/// Text is serialized as a string. Binary data is serialized as bytes in binary
+/// encodings and as `{ "base64": "..." }` in JSON. Either form is accepted when
+/// deserializing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Contents {
+  Text(String),
+  Binary(Vec<u8>)
+}
+
+impl Default for Contents {
+  fn default() -> Self {
+    Self::Text(String::new())
+  }
+}
+
+impl From<String> for Contents {
+  fn from(text: String) -> Self {
+    Self::Text(text)
+  }
+}
+
+impl Contents {
+  /// Text if `bytes` are UTF-8 without any NULs, which no text file has, otherwise binary
+  pub fn from_bytes(bytes: Vec<u8>) -> Self {
+    if bytes.contains(&0) {
+      return Self::Binary(bytes);
+    }
+
+    match String::from_utf8(bytes) {
+      Ok(text) => Self::Text(text),
+      Err(e) => Self::Binary(e.into_bytes())
+    }
+  }
+
+  pub fn encoding(&self) -> FileEncoding {
+    match self {
+      Self::Text(_) => FileEncoding::Utf8,
+      Self::Binary(_) => FileEncoding::Binary
+    }
+  }
+
+  pub fn as_text(&self) -> Option<&str> {
+    match self {
+      Self::Text(text) => Some(text),
+      Self::Binary(_) => None
+    }
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    match self {
+      Self::Text(text) => text.as_bytes(),
+      Self::Binary(bytes) => bytes
+    }
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    match self {
+      Self::Text(text) => text.into_bytes(),
+      Self::Binary(bytes) => bytes
+    }
+  }
+
+  /// Size in bytes
+  pub fn len(&self) -> usize {
+    self.as_bytes().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl Serialize for Contents {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self {
+      Self::Text(text) => serializer.serialize_str(text),
+      Self::Binary(bytes) if serializer.is_human_readable() => {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry("base64", &base64::encode(bytes))?;
+        map.end()
+      },
+      Self::Binary(bytes) => serializer.serialize_bytes(bytes)
+    }
+  }
+}
+
+struct ContentsVisitor;
+
+impl<'de> Visitor<'de> for ContentsVisitor {
+  type Value = Contents;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a string, bytes or { \"base64\": string }")
+  }
+
+  fn visit_str<E: de::Error>(self, v: &str) -> Result<Contents, E> {
+    Ok(Contents::Text(v.to_string()))
+  }
+
+  fn visit_string<E: de::Error>(self, v: String) -> Result<Contents, E> {
+    Ok(Contents::Text(v))
+  }
+
+  fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Contents, E> {
+    Ok(Contents::Binary(v.to_vec()))
+  }
+
+  fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Contents, E> {
+    Ok(Contents::Binary(v))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Contents, A::Error> {
+    let mut ret = None;
+
+    while let Some(key) = map.next_key::<String>()? {
+      match key.as_str() {
+        "base64" => {
+          let encoded: String = map.next_value()?;
+          ret = Some(base64::decode(&encoded).map_err(de::Error::custom)?);
+        },
+        other => return Err(de::Error::unknown_field(other, &["base64"]))
+      }
+    }
+
+    ret.map(Contents::Binary).ok_or_else(|| de::Error::missing_field("base64"))
+  }
+}
+
+impl<'de> Deserialize<'de> for Contents {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(ContentsVisitor)
+  }
+}
+
+/// The MIME type of a project file from its extension alone, if it's one we know
+pub fn known_mime_type(path: &Path) -> Option<&'static str> {
+  let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+
+  Some(match ext.as_deref()? {
+    "c" | "h" => "text/x-c",
+    "cpp" | "cc" | "cxx" | "hpp" | "hh" => "text/x-c++",
+    "py" => "text/x-python",
+    "txt" | "log" => "text/plain",
+    "md" => "text/markdown",
+    "csv" => "text/csv",
+    "json" => "application/json",
+    "png" => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif" => "image/gif",
+    "bmp" => "image/bmp",
+    "svg" => "image/svg+xml",
+    "wav" => "audio/wav",
+    "zip" => "application/zip",
+    _ => return None
+  })
+}
+
+/// The MIME type of a project file, falling back on its detected encoding
+pub fn mime_type(path: &Path, encoding: FileEncoding) -> &'static str {
+  known_mime_type(path).unwrap_or(match encoding {
+    FileEncoding::Utf8 => "text/plain",
+    FileEncoding::Binary => "application/octet-stream"
+  })
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct File {
-  contents: String
+  encoding: FileEncoding,
+  mime: String,
+  contents: Contents
 }
 
 impl File {
   pub async fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-    let contents = tokio::fs::read_to_string(path).await?;
+    let contents = Contents::from_bytes(tokio::fs::read(&path).await?);
     
     Ok(Self {
+      encoding: contents.encoding(),
+      mime: mime_type(path.as_ref(), contents.encoding()).to_string(),
       contents
     })
   }
@@ -36,7 +226,7 @@ pub struct Folder {
 impl Folder {
   pub fn read<P: 'static + Send + Sync + AsRef<Path>>(path: P) -> Pin<Box<dyn Future<Output = anyhow::Result<Self>> + Send>> {
     Box::pin(async {
-      let read_dir = tokio::fs::read_dir(&path).await?;
+      let mut read_dir = tokio::fs::read_dir(&path).await?;
     
       let mut ret = Self {
         entries: HashMap::new()
@@ -57,6 +247,10 @@ impl Folder {
     })
     
   }
+
+  pub fn remove(&mut self, name: &str) -> Option<Entry> {
+    self.entries.remove(name)
+  }
 }
 
 #[derive(From, Debug, Serialize, Deserialize)]
@@ -81,8 +275,237 @@ impl Entry {
   }
 }
 
-pub fn tmp_dir() -> std::path::PathBuf {
-  let mut dir = std::env::temp_dir();
-  dir.push("ivygate");
-  dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+  File,
+  Folder
+}
+
+/// An entry's name and metadata, without its contents
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EntryInfo {
+  pub name: String,
+  pub kind: EntryKind,
+
+  /// In bytes. Always 0 for folders.
+  pub size: u64,
+
+  /// Guessed from a file's extension, since its contents aren't read
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub mime: Option<String>,
+
+  /// Last modification, in seconds since the Unix epoch, if the platform records it
+  pub modified: Option<u64>,
+
+  /// A folder's entries, or `None` if the listing stopped above them
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub entries: Option<Vec<EntryInfo>>
+}
+
+impl EntryInfo {
+  /// Lists the folder at `path`, sorted by name. `Some(1)` lists only its own entries,
+  /// `Some(2)` their entries too, and so on. `None` lists the whole tree, and `Some(0)`
+  /// nothing at all, though the folder must still exist.
+  pub fn list<P: 'static + Send + Sync + AsRef<Path>>(path: P, depth: Option<usize>) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Self>>> + Send>> {
+    Box::pin(async move {
+      let mut read_dir = tokio::fs::read_dir(&path).await?;
+      let mut ret = Vec::new();
+
+      if depth == Some(0) {
+        return Ok(ret);
+      }
+
+      while let Some(entry) = read_dir.next_entry().await? {
+        // Doesn't follow symlinks, so they're skipped like anything else that isn't a file or folder
+        let metadata = entry.metadata().await?;
+        let name = match entry.file_name().into_string() {
+          Ok(name) => name,
+          Err(_) => continue
+        };
+
+        let mime = known_mime_type(Path::new(&name)).filter(|_| metadata.is_file()).map(String::from);
+        let (kind, size, entries) = if metadata.is_dir() {
+          let entries = match depth {
+            Some(1) => None,
+            _ => Some(Self::list(entry.path(), depth.map(|d| d - 1)).await?)
+          };
+          (EntryKind::Folder, 0, entries)
+        } else if metadata.is_file() {
+          (EntryKind::File, metadata.len(), None)
+        } else {
+          continue
+        };
+
+        ret.push(Self {
+          name,
+          kind,
+          size,
+          mime,
+          modified: metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs()),
+          entries
+        });
+      }
+
+      ret.sort_by(|a, b| a.name.cmp(&b.name));
+      Ok(ret)
+    })
+  }
+}
+
+/// The size of every file in a listing, with paths relative to the listed folder
+pub fn file_sizes(entries: &[EntryInfo]) -> Vec<(PathBuf, u64)> {
+  let mut ret = Vec::new();
+
+  for entry in entries {
+    match (&entry.kind, &entry.entries) {
+      (EntryKind::File, _) => ret.push((PathBuf::from(&entry.name), entry.size)),
+      (EntryKind::Folder, Some(entries)) => ret.extend(
+        file_sizes(entries).into_iter().map(|(path, size)| (Path::new(&entry.name).join(path), size))
+      ),
+      (EntryKind::Folder, None) => {}
+    }
+  }
+
+  ret
+}
+
+/// Scratch space for this process's compiler sessions. Only this process uses it,
+/// so it can be removed on shutdown.
+pub fn tmp_dir() -> PathBuf {
+  TMP_DIR.clone()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A scratch folder, removed when dropped
+  struct Scratch(PathBuf);
+
+  impl Scratch {
+    fn new() -> Self {
+      let path = std::env::temp_dir().join(format!("ivygate-fs-{}", Uuid::new_v4()));
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+    }
+  }
+
+  impl Drop for Scratch {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  /// Bytes that aren't valid UTF-8
+  const BINARY: &[u8] = &[0x89, b'P', b'N', b'G', 0xff, 0xfe];
+
+  #[test]
+  fn reads_text_only_from_utf8_without_nuls() {
+    assert_eq!(Contents::from_bytes(b"int main() {}".to_vec()), Contents::Text("int main() {}".into()));
+    assert_eq!(Contents::from_bytes(Vec::new()), Contents::Text(String::new()));
+
+    // Valid UTF-8, but no text file has a NUL
+    assert_eq!(Contents::from_bytes(b"a\0b".to_vec()), Contents::Binary(b"a\0b".to_vec()));
+    assert_eq!(Contents::from_bytes(BINARY.to_vec()), Contents::Binary(BINARY.to_vec()));
+  }
+
+  #[test]
+  fn serializes_binary_as_base64_in_json() {
+    let contents = Contents::Binary(BINARY.to_vec());
+    let json = serde_json::to_value(&contents).unwrap();
+    assert_eq!(json, serde_json::json!({ "base64": base64::encode(BINARY) }));
+    assert_eq!(serde_json::from_value::<Contents>(json).unwrap(), contents);
+
+    let text = Contents::Text("int main() {}".into());
+    assert_eq!(serde_json::to_value(&text).unwrap(), serde_json::json!("int main() {}"));
+    assert_eq!(serde_json::from_str::<Contents>("\"int main() {}\"").unwrap(), text);
+
+    assert!(serde_json::from_str::<Contents>("{ \"base64\": \"not base64!\" }").is_err());
+    assert!(serde_json::from_str::<Contents>("{ \"hex\": \"89\" }").is_err());
+  }
+
+  #[test]
+  fn serializes_binary_as_raw_bytes_in_msgpack() {
+    let contents = Contents::Binary(BINARY.to_vec());
+    let encoded = rmp_serde::to_vec_named(&contents).unwrap();
+
+    // bin 8, then the length and the bytes themselves
+    assert_eq!(&encoded[..2], &[0xc4, BINARY.len() as u8]);
+    assert_eq!(&encoded[2..], BINARY);
+    assert_eq!(rmp_serde::from_slice::<Contents>(&encoded).unwrap(), contents);
+
+    let text = Contents::Text("int main() {}".into());
+    assert_eq!(rmp_serde::from_slice::<Contents>(&rmp_serde::to_vec_named(&text).unwrap()).unwrap(), text);
+  }
+
+  #[test]
+  fn serializes_binary_as_raw_bytes_in_cbor() {
+    let contents = Contents::Binary(BINARY.to_vec());
+    let mut encoded = Vec::new();
+    ciborium::ser::into_writer(&contents, &mut encoded).unwrap();
+
+    // A byte string with the length in the initial byte
+    assert_eq!(encoded[0], 0x40 | BINARY.len() as u8);
+    assert_eq!(&encoded[1..], BINARY);
+    assert_eq!(ciborium::de::from_reader::<Contents, _>(encoded.as_slice()).unwrap(), contents);
+
+    let text = Contents::Text("int main() {}".into());
+    let mut encoded = Vec::new();
+    ciborium::ser::into_writer(&text, &mut encoded).unwrap();
+    assert_eq!(ciborium::de::from_reader::<Contents, _>(encoded.as_slice()).unwrap(), text);
+  }
+
+  fn names(entries: &[EntryInfo]) -> Vec<&str> {
+    entries.iter().map(|entry| entry.name.as_str()).collect()
+  }
+
+  /// `main.c`, `src/lib.c` and `src/util/util.c`
+  fn tree() -> Scratch {
+    let scratch = Scratch::new();
+    std::fs::create_dir_all(scratch.0.join("src/util")).unwrap();
+    std::fs::write(scratch.0.join("main.c"), "int main() {}").unwrap();
+    std::fs::write(scratch.0.join("src/lib.c"), "").unwrap();
+    std::fs::write(scratch.0.join("src/util/util.c"), "").unwrap();
+    scratch
+  }
+
+  #[tokio::test]
+  async fn lists_nothing_at_depth_zero() {
+    let scratch = tree();
+    assert!(EntryInfo::list(scratch.0.clone(), Some(0)).await.unwrap().is_empty());
+
+    // The folder is still looked at
+    assert!(EntryInfo::list(scratch.0.join("missing"), Some(0)).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn lists_one_level_at_depth_one() {
+    let scratch = tree();
+    let entries = EntryInfo::list(scratch.0.clone(), Some(1)).await.unwrap();
+
+    assert_eq!(names(&entries), ["main.c", "src"]);
+    assert_eq!(entries[0].kind, EntryKind::File);
+    assert_eq!(entries[0].size, 13);
+    assert_eq!(entries[1].kind, EntryKind::Folder);
+    assert!(entries[1].entries.is_none());
+  }
+
+  #[tokio::test]
+  async fn lists_the_whole_tree_without_a_depth() {
+    let scratch = tree();
+    let entries = EntryInfo::list(scratch.0.clone(), None).await.unwrap();
+
+    assert_eq!(names(&entries), ["main.c", "src"]);
+    let src = entries[1].entries.as_ref().unwrap();
+    assert_eq!(names(src), ["lib.c", "util"]);
+    assert_eq!(names(src[1].entries.as_ref().unwrap()), ["util.c"]);
+
+    // Stops partway down with a depth
+    let entries = EntryInfo::list(scratch.0.clone(), Some(2)).await.unwrap();
+    assert_eq!(names(entries[1].entries.as_ref().unwrap()), ["lib.c", "util"]);
+    assert!(entries[1].entries.as_ref().unwrap()[1].entries.is_none());
+  }
 }
\ No newline at end of file
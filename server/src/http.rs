@@ -0,0 +1,551 @@
+//! Plain HTTP endpoints for non-interactive clients (e.g., grading scripts),
+//! served on the same port as the websocket protocol. Requests carrying
+//! `Upgrade: websocket` are handed to `ws::serve`, and anything outside
+//! `/api` is a static file (see `assets`).
+//!
+//! Every endpoint except the health check authenticates with HTTP Basic auth,
+//! where a username containing `@` is treated as an email address, or with a
+//! session token from `LoginRes` as `Authorization: Bearer <token>`.
+//!
+//! - `GET /api/health`
+//! - `GET /api/projects`
+//! - `GET /api/projects/{uuid}`: the whole project as an `fs::Folder`
+//! - `GET /api/projects/{uuid}/tree/{path}?depth={n}`: `fs::EntryInfo`s without contents.
+//!   The path defaults to the project's root and the depth to the whole tree.
+//! - `GET /api/projects/{uuid}/diagnostics`: compiles every file once
+//! - `GET /api/projects/{uuid}/files/{path}`: the raw contents, typed by extension
+//! - `PUT /api/projects/{uuid}/files/{path}`: text or binary
+//! - `GET /metrics`: Prometheus metrics, unless disabled in the configuration
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use tracing::{debug, info_span, warn, Instrument};
+use tungstenite::handshake::derive_accept_key;
+use uuid::Uuid;
+
+use crate::assets;
+use crate::backing::{Backing, Project, UserBacking};
+use crate::config::ConnectionConfig;
+use crate::fs::{self, Contents, FileEncoding};
+use crate::inc::{Message, SpawnError, Target};
+use crate::metrics;
+use crate::path::ProjectPath;
+use crate::quota::{self, RateLimiter};
+use crate::proto::*;
+use crate::shutdown::Shutdown;
+use crate::token;
+use crate::ws;
+use crate::INC_SPAWNER;
+
+#[derive(Debug, Serialize)]
+struct Health {
+  status: &'static str,
+  version: u32
+}
+
+/// Diagnostics for every file in a project that has an incremental compiler
+#[derive(Debug, Serialize)]
+struct Diagnostics {
+  target: Target,
+  files: BTreeMap<PathBuf, Vec<Message>>
+}
+
+/// Decodes `%XX` escapes, or `None` if an escape is malformed or the result isn't UTF-8
+pub fn percent_decode(s: &str) -> Option<String> {
+  let mut bytes = Vec::with_capacity(s.len());
+  let mut iter = s.bytes();
+  while let Some(b) = iter.next() {
+    if b != b'%' {
+      bytes.push(b);
+      continue
+    }
+
+    // Exactly two hex digits, so truncated escapes like `%A` are rejected
+    let hi = iter.next().and_then(|h| (h as char).to_digit(16))?;
+    let lo = iter.next().and_then(|l| (l as char).to_digit(16))?;
+    bytes.push((hi * 16 + lo) as u8);
+  }
+
+  String::from_utf8(bytes).ok()
+}
+
+fn status(code: ErrorCode) -> StatusCode {
+  match code {
+    ErrorCode::NotFound | ErrorCode::ProjectNotOpen | ErrorCode::NoSuchHandle => StatusCode::NOT_FOUND,
+    ErrorCode::AuthFailed | ErrorCode::NotLoggedIn => StatusCode::UNAUTHORIZED,
+    ErrorCode::NoInc | ErrorCode::UnsupportedEntry => StatusCode::UNPROCESSABLE_ENTITY,
+    ErrorCode::MalformedFrame | ErrorCode::MalformedRequest | ErrorCode::IncompatibleVersion | ErrorCode::InvalidPath => StatusCode::BAD_REQUEST,
+    ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+    ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR
+  }
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+  match serde_json::to_vec(value) {
+    Ok(body) => Response::builder()
+      .status(status)
+      .header(header::CONTENT_TYPE, "application/json")
+      .body(Body::from(body))
+      .unwrap(),
+    Err(e) => error(Error::new(ErrorCode::Internal, e.to_string()))
+  }
+}
+
+fn error(error: Error) -> Response<Body> {
+  let mut ret = json(status(error.code), &error);
+
+  if error.code == ErrorCode::AuthFailed || error.code == ErrorCode::NotLoggedIn {
+    ret.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"ivygate\""));
+  }
+
+  ret
+}
+
+pub fn empty(status: StatusCode) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+/// Whether the client is asking to switch the connection to a websocket
+fn is_upgrade(req: &Request<Body>) -> bool {
+  let has_token = |name: header::HeaderName, token: &str| req.headers().get_all(name).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|value| value.trim().eq_ignore_ascii_case(token));
+
+  has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+/// Whether a websocket handshake's `Origin` is allowed to connect (see
+/// `ConnectionConfig::allowed_origins`). Browsers always send one, so this is what stops
+/// other sites' pages from talking to the server, including through DNS rebinding.
+fn origin_allowed(headers: &HeaderMap, allowed: &[String]) -> bool {
+  let origin = match headers.get(header::ORIGIN) {
+    Some(origin) => match origin.to_str() {
+      Ok(origin) => origin,
+      Err(_) => return false
+    },
+    None => return true
+  };
+
+  if allowed.iter().any(|a| a == "*" || a.trim_end_matches('/').eq_ignore_ascii_case(origin)) {
+    return true;
+  }
+
+  // Otherwise only our own pages, and only under a name a rebinding attack can't control
+  let host = match headers.get(header::HOST).and_then(|host| host.to_str().ok()) {
+    Some(host) => host,
+    None => return false
+  };
+  let same_origin = matches!(origin.split_once("://"), Some((_, authority)) if authority.eq_ignore_ascii_case(host));
+
+  let hostname = match host.strip_prefix('[') {
+    Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+    None => host.rsplit_once(':').map_or(host, |(hostname, _)| hostname)
+  };
+
+  same_origin && (hostname.eq_ignore_ascii_case("localhost") || hostname.parse::<IpAddr>().is_ok())
+}
+
+/// Reads the credentials from an `Authorization: Basic` header
+fn credentials(headers: &HeaderMap) -> Result<User, Error> {
+  let missing = || Error::new(ErrorCode::NotLoggedIn, "Basic authorization is required");
+
+  let value = headers.get(header::AUTHORIZATION).ok_or_else(missing)?;
+  let encoded = value.to_str().ok().and_then(|v| v.strip_prefix("Basic ")).ok_or_else(missing)?;
+
+  let malformed = || Error::new(ErrorCode::MalformedRequest, "Malformed authorization header");
+  let decoded = base64::decode(encoded.trim()).map_err(|_| malformed())?;
+  let decoded = String::from_utf8(decoded).map_err(|_| malformed())?;
+  let (name, password) = decoded.split_once(':').ok_or_else(malformed)?;
+
+  Ok(User {
+    ident: if name.contains('@') { Ident::email(name) } else { Ident::username(name) },
+    password: password.to_string()
+  })
+}
+
+/// The session token from an `Authorization: Bearer` header
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+  headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+
+/// The `Ident` keying the requester's quotas
+fn ident(headers: &HeaderMap) -> Result<String, Error> {
+  match bearer(headers) {
+    Some(token) => Ok(token::verify(token).map_err(|e| Error::new(ErrorCode::AuthFailed, e.to_string()))?.ident.to_string()),
+    None => Ok(credentials(headers)?.ident.to_string())
+  }
+}
+
+fn project_uuid(segment: &str) -> Result<Uuid, Error> {
+  Uuid::parse_str(segment).map_err(|_| Error::new(ErrorCode::NotFound, format!("{} is not a project", segment)))
+}
+
+/// Decodes the path segments following `/files` or `/tree`, or `None` if there are none
+fn folder_path(segments: &[&str]) -> Result<Option<ProjectPath>, Error> {
+  let mut ret = PathBuf::new();
+
+  for segment in segments.iter().filter(|segment| !segment.is_empty()) {
+    let decoded = percent_decode(segment)
+      .ok_or_else(|| Error::new(ErrorCode::MalformedRequest, "Malformed path"))?;
+    ret.push(decoded);
+  }
+
+  if ret.as_os_str().is_empty() {
+    return Ok(None);
+  }
+
+  // Decoded segments may hold `/`, so this is where `..` and absolute paths are caught
+  Ok(Some(ProjectPath::new(ret)?))
+}
+
+fn file_path(segments: &[&str]) -> Result<ProjectPath, Error> {
+  folder_path(segments)?.ok_or_else(|| Error::new(ErrorCode::NotFound, "A file path is required"))
+}
+
+/// The value of `name` in the query string, if present
+fn query<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+  req.uri().query()?.split('&').find_map(|pair| match pair.split_once('=') {
+    Some((key, value)) if key == name => Some(value),
+    _ => None
+  })
+}
+
+/// State shared by every request on one HTTP connection
+#[derive(Clone)]
+struct Context {
+  config: ConnectionConfig,
+  backing: Arc<dyn Backing>,
+  shutdown: Shutdown,
+
+  /// Limits API requests like a websocket connection's
+  rate: Arc<Mutex<RateLimiter>>
+}
+
+impl Context {
+  async fn handle(self, req: Request<Body>) -> Response<Body> {
+    if is_upgrade(&req) {
+      return self.upgrade(req);
+    }
+
+    let span = info_span!("http", method = %req.method(), path = req.uri().path());
+    async {
+      match self.route(req).await {
+        Ok(res) => res,
+        Err(e) => {
+          debug!(code = e.code.name(), "{}", e.message);
+          error(e)
+        }
+      }
+    }.instrument(span).await
+  }
+
+  /// Completes the websocket handshake, then serves the websocket once hyper releases the connection
+  fn upgrade(self, req: Request<Body>) -> Response<Body> {
+    let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+      Some(key) if matches!(req.headers().get(header::SEC_WEBSOCKET_VERSION), Some(v) if v == "13") => key,
+      _ => return empty(StatusCode::BAD_REQUEST)
+    };
+
+    if !origin_allowed(req.headers(), &self.config.allowed_origins) {
+      warn!(origin = ?req.headers().get(header::ORIGIN), "Rejected websocket from another site");
+      return empty(StatusCode::FORBIDDEN);
+    }
+
+    let accept = derive_accept_key(key.as_bytes());
+    let path = req.uri().path().to_string();
+
+    let Self { config, backing, shutdown, .. } = self;
+    tokio::spawn(async move {
+      let upgraded = match hyper::upgrade::on(req).await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+          warn!("Websocket upgrade failed: {}", e);
+          return;
+        }
+      };
+
+      if let Err(e) = ws::serve(Box::new(upgraded), &path, config, backing, shutdown).await {
+        warn!("Websocket failed: {}", e);
+      }
+    }.in_current_span());
+
+    Response::builder()
+      .status(StatusCode::SWITCHING_PROTOCOLS)
+      .header(header::CONNECTION, "Upgrade")
+      .header(header::UPGRADE, "websocket")
+      .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  async fn route(&self, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    if let ["api", endpoint, ..] = segments.as_slice() {
+      if *endpoint != "health" {
+        self.rate.lock().unwrap().check()?;
+      }
+    }
+
+    match (method, segments.as_slice()) {
+      (Method::GET, ["api", "health"]) => Ok(json(StatusCode::OK, &Health {
+        status: "ok",
+        version: PROTOCOL_VERSION
+      })),
+      (Method::GET, ["api", "projects"]) => {
+        let user = self.login(req.headers()).await?;
+        Ok(json(StatusCode::OK, &user.projects().await?))
+      },
+      (Method::GET, ["api", "projects", uuid]) => {
+        let project = self.project(req.headers(), uuid).await?;
+        Ok(json(StatusCode::OK, &project.root().await?))
+      },
+      (Method::GET, ["api", "projects", uuid, "tree", rest @ ..]) => {
+        let path = folder_path(rest)?;
+        let depth = match query(&req, "depth") {
+          Some(depth) => Some(depth.parse::<usize>().map_err(|_| Error::new(ErrorCode::MalformedRequest, "depth must be a number"))?),
+          None => None
+        };
+
+        let project = self.project(req.headers(), uuid).await?;
+        let entries = project.list(path.clone(), depth).await.map_err(|e| match &path {
+          Some(path) => Error::from(e).with_path(path),
+          None => Error::from(e)
+        })?;
+        Ok(json(StatusCode::OK, &entries))
+      },
+      (Method::GET, ["api", "projects", uuid, "diagnostics"]) => {
+        let project = self.project(req.headers(), uuid).await?;
+        let ident = ident(req.headers())?;
+        Ok(json(StatusCode::OK, &diagnostics(&ident, project_uuid(uuid)?, project.as_ref()).await?))
+      },
+      (Method::GET, ["api", "projects", uuid, "files", rest @ ..]) => {
+        let path = file_path(rest)?;
+        let project = self.project(req.headers(), uuid).await?;
+        let contents = project.read(path.clone()).await.map_err(|e| Error::from(e).with_path(&path))?;
+
+        let content_type = match contents.encoding() {
+          FileEncoding::Utf8 => format!("{}; charset=utf-8", fs::mime_type(&path, FileEncoding::Utf8)),
+          FileEncoding::Binary => fs::mime_type(&path, FileEncoding::Binary).to_string()
+        };
+
+        Ok(Response::builder()
+          .header(header::CONTENT_TYPE, content_type)
+          .body(Body::from(contents.into_bytes()))
+          .unwrap())
+      },
+      (Method::PUT, ["api", "projects", uuid, "files", rest @ ..]) => {
+        let path = file_path(rest)?;
+        let mut project = self.project(req.headers(), uuid).await?;
+
+        // Checked as it arrives, so an oversized upload is never buffered in full
+        let mut body = req.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+          let chunk = chunk.map_err(|e| Error::new(ErrorCode::MalformedRequest, e.to_string()))?;
+          bytes.extend_from_slice(&chunk);
+          quota::check_file_size(&path, bytes.len() as u64)?;
+        }
+
+        let contents = Contents::from_bytes(bytes);
+
+        let others: u64 = fs::file_sizes(&project.list(None, None).await?).into_iter()
+          .filter(|(p, _)| p.as_path() != path.as_ref())
+          .map(|(_, size)| size)
+          .sum();
+        quota::check_project_size(others + contents.len() as u64)?;
+
+        project.save(path.clone(), contents).await.map_err(|e| Error::from(e).with_path(&path))?;
+        project.flush().await?;
+        Ok(empty(StatusCode::NO_CONTENT))
+      },
+      (Method::GET, ["metrics"]) if crate::config::get().metrics => {
+        let (content_type, body) = metrics::gather()?;
+        Ok(Response::builder()
+          .header(header::CONTENT_TYPE, content_type)
+          .body(Body::from(body))
+          .unwrap())
+      },
+      (_, ["api", ..]) => Err(Error::new(ErrorCode::NotFound, format!("No such endpoint {}", path))),
+      (Method::GET, _) | (Method::HEAD, _) => Ok(match &crate::config::get().static_dir {
+        Some(root) => assets::serve(root, &path, req.headers()).await,
+        None => empty(StatusCode::NOT_FOUND)
+      }),
+      _ => Ok(empty(StatusCode::NOT_FOUND))
+    }
+  }
+
+  /// Authenticates the request, which then counts against the user's shared request rate
+  async fn login(&self, headers: &HeaderMap) -> Result<Box<dyn UserBacking>, Error> {
+    let (ident, user) = match bearer(headers) {
+      Some(token) => {
+        let claims = token::verify(token).map_err(|e| Error::new(ErrorCode::AuthFailed, e.to_string()))?;
+        (claims.ident.to_string(), self.backing.resume(claims.ident).await?)
+      },
+      None => {
+        let user = credentials(headers)?;
+        (user.ident.to_string(), self.backing.login(user).await?)
+      }
+    };
+
+    quota::user_rate(&ident).lock().unwrap().check()?;
+    Ok(user)
+  }
+
+  async fn project(&self, headers: &HeaderMap, uuid: &str) -> Result<Box<dyn Project>, Error> {
+    let uuid = project_uuid(uuid)?;
+    let mut user = self.login(headers).await?;
+    Ok(user.open_project(uuid).await?)
+  }
+}
+
+/// Compiles every file in `project` once, skipping files no incremental compiler supports.
+/// Each file counts against `ident`'s quotas as if it were opened and compiled over the websocket.
+async fn diagnostics(ident: &str, uuid: Uuid, project: &dyn Project) -> Result<Diagnostics, Error> {
+  let target = project.target().await?;
+  let listing = project.list(None, None).await?;
+
+  let mut files = BTreeMap::new();
+  for (path, size) in fs::file_sizes(&listing) {
+    let path = ProjectPath::new(path)?;
+
+    let _session_permit = quota::session(ident).map_err(|e| e.with_path(&path))?;
+    let session_path = PathBuf::from(uuid.to_string()).join(&path);
+    let mut session = match INC_SPAWNER.spawn(&session_path, target.clone()).await {
+      Ok(session) => session,
+      Err(e) => match e.downcast_ref::<SpawnError>() {
+        Some(SpawnError::NoInc) => continue,
+        None => return Err(Error::from(e).with_path(path))
+      }
+    };
+    quota::check_file_size(&path, size)?;
+
+    // Only files with a compiler are read, and binary ones are skipped
+    let contents = match project.read(path.clone()).await.map_err(|e| Error::from(e).with_path(&path))? {
+      Contents::Text(text) => text,
+      Contents::Binary(_) => continue
+    };
+    let _build = quota::build(ident).map_err(|e| e.with_path(&path))?;
+    let messages = session.update(Some(contents)).await
+      .map_err(|e| Error::from(e).with_path(&path))?;
+    files.insert(path.into(), messages);
+  }
+
+  Ok(Diagnostics {
+    target,
+    files
+  })
+}
+
+/// Serves HTTP requests on `stream` until the client disconnects or the server shuts down
+pub async fn serve(
+  stream: Box<dyn ws::Stream>,
+  config: ConnectionConfig,
+  backing: Arc<dyn Backing>,
+  mut shutdown: Shutdown
+) -> anyhow::Result<()> {
+  let context = Context {
+    config,
+    backing,
+    shutdown: shutdown.clone(),
+    rate: Arc::new(Mutex::new(RateLimiter::default()))
+  };
+
+  let service = service_fn(move |req| {
+    let context = context.clone();
+    async move { Ok::<_, Infallible>(context.handle(req).await) }
+  });
+
+  let conn = Http::new().serve_connection(stream, service).with_upgrades();
+  tokio::pin!(conn);
+
+  tokio::select! {
+    result = &mut conn => result?,
+    _ = shutdown.wait() => {
+      // Finishes the request in progress, if any
+      conn.as_mut().graceful_shutdown();
+      conn.await?;
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn percent_decodes() {
+    assert_eq!(percent_decode("main.c").as_deref(), Some("main.c"));
+    assert_eq!(percent_decode("my%20file.c").as_deref(), Some("my file.c"));
+    assert_eq!(percent_decode("%2e%2E").as_deref(), Some(".."));
+    assert_eq!(percent_decode("%C3%A9").as_deref(), Some("\u{e9}"));
+  }
+
+  #[test]
+  fn rejects_malformed_escapes() {
+    for s in &["%", "%A", "a%4", "%G0", "%+F", "%-1", "%FF"] {
+      assert_eq!(percent_decode(s), None, "{:?} decoded", s);
+    }
+  }
+
+  fn headers(origin: &[u8], host: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ORIGIN, HeaderValue::from_bytes(origin).unwrap());
+    headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+    headers
+  }
+
+  #[test]
+  fn allows_clients_without_an_origin() {
+    assert!(origin_allowed(&HeaderMap::new(), &[]));
+  }
+
+  #[test]
+  fn allows_listed_origins() {
+    let allowed = vec!["https://IDE.example.com/".to_string()];
+    assert!(origin_allowed(&headers(b"https://ide.example.com", "ivygate.example.com"), &allowed));
+    assert!(!origin_allowed(&headers(b"https://evil.example.com", "ivygate.example.com"), &allowed));
+    assert!(origin_allowed(&headers(b"https://evil.example.com", "ivygate.example.com"), &["*".to_string()]));
+  }
+
+  #[test]
+  fn allows_same_origin_on_loopback_names() {
+    assert!(origin_allowed(&headers(b"http://localhost:8080", "localhost:8080"), &[]));
+    assert!(origin_allowed(&headers(b"http://127.0.0.1:8080", "127.0.0.1:8080"), &[]));
+    assert!(origin_allowed(&headers(b"http://[::1]:8080", "[::1]:8080"), &[]));
+    assert!(origin_allowed(&headers(b"http://192.168.1.2", "192.168.1.2"), &[]));
+  }
+
+  #[test]
+  fn rejects_same_origin_on_dns_names() {
+    // A rebound name would look exactly like this
+    assert!(!origin_allowed(&headers(b"http://ivygate.example.com", "ivygate.example.com"), &[]));
+  }
+
+  #[test]
+  fn rejects_cross_origin() {
+    assert!(!origin_allowed(&headers(b"http://localhost:3000", "localhost:8080"), &[]));
+    assert!(!origin_allowed(&headers(b"http://evil.example.com", "127.0.0.1:8080"), &[]));
+    assert!(!origin_allowed(&headers(b"null", "localhost:8080"), &[]));
+  }
+
+  #[test]
+  fn rejects_non_utf8_origins() {
+    assert!(!origin_allowed(&headers(b"http://\xff", "localhost"), &["*".to_string()]));
+  }
+}
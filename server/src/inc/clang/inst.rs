@@ -2,10 +2,12 @@
 
 use tokio::sync::oneshot::Sender as OneshotSender;
 
-use crate::inc::{Message, Severity, Index, Range};
+use crate::inc::{Message, Severity, Index, Range, Target, Completion};
+use crate::metrics;
+use tracing::debug;
 use async_trait::async_trait;
 
-use clang::{Clang, Index as CIndex, TranslationUnit, source::SourceRange, source::SourceLocation, diagnostic::{Diagnostic, Severity as DSeverity}};
+use clang::{Clang, Index as CIndex, TranslationUnit, Unsaved, source::SourceRange, source::SourceLocation, diagnostic::{Diagnostic, Severity as DSeverity}};
 
 use meio::{Actor, StartedBy, Context, ActionHandler, Action};
 
@@ -13,6 +15,7 @@ use std::path::{Path, PathBuf, Component};
 
 use derive_more::*;
 use lazy_static::lazy_static;
+use uuid::Uuid;
 
 use tokio::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
 
@@ -29,6 +32,14 @@ pub enum Req {
   Compile {
     code: Option<String>,
     tx: OneshotSender<anyhow::Result<Vec<Message>>>
+  },
+  Complete {
+    at: Index,
+    tx: OneshotSender<anyhow::Result<Vec<Completion>>>
+  },
+  Hover {
+    at: Index,
+    tx: OneshotSender<anyhow::Result<Option<String>>>
   }
 }
 
@@ -94,47 +105,100 @@ async fn compile<'a>(tu: &TranslationUnit<'a>) -> anyhow::Result<Vec<Message>> {
   Ok(ret)
 }
 
+fn complete<'a>(tu: &TranslationUnit<'a>, path: &Path, unsaved: &[Unsaved], at: &Index) -> Vec<Completion> {
+  let mut completer = tu.completer(path, at.line as u32, at.col as u32);
+  completer.unsaved(unsaved);
+
+  completer.complete().get_results().into_iter().filter_map(|result| {
+    Some(Completion {
+      label: result.string.get_typed_text()?,
+      detail: result.string.get_comment_brief()
+    })
+  }).collect()
+}
+
+fn hover<'a>(tu: &TranslationUnit<'a>, path: &Path, at: &Index) -> Option<String> {
+  let entity = tu.get_file(path)?.get_location(at.line as u32, at.col as u32).get_entity()?;
+
+  let mut ret = match entity.get_type() {
+    Some(ty) => format!("{} {}", ty.get_display_name(), entity.get_display_name()?),
+    None => entity.get_display_name()?
+  };
+
+  if let Some(brief) = entity.get_comment_brief() {
+    ret.push_str("\n\n");
+    ret.push_str(&brief);
+  }
+
+  Some(ret)
+}
+
 lazy_static! {
   static ref CLANG: Clang = Clang::new().unwrap();
 
 }
 
-pub async fn instance<P: AsRef<Path>>(mut rx: MpscReceiver<Req>, path: P) -> anyhow::Result<()> {
-  let path = path.as_ref();
+pub async fn instance<P: AsRef<Path>>(rx: MpscReceiver<Req>, path: P, target: Target, flags: Vec<String>) -> anyhow::Result<()> {
+  // Each session parses its own copy, so sessions for the same file (e.g., open in two
+  // tabs) can't overwrite or delete the one another is parsing
+  let session_dir = crate::fs::tmp_dir().join(Uuid::new_v4().to_string());
 
-  let tmp_dir = crate::fs::tmp_dir();
-  
-  let mut tmp_path = tmp_dir.clone();
-  for c in path.components().filter(|c| match c {
+  let mut tmp_path = session_dir.clone();
+  for c in path.as_ref().components().filter(|c| match c {
     Component::RootDir | Component::Prefix(_) => false,
     _ => true
   }) {
     tmp_path.push(c);
   }
 
+  let ret = run(rx, &tmp_path, target, flags).await;
+
+  // The session was dropped (or failed), so nobody will ask about this copy again
+  let _ = tokio::fs::remove_dir_all(&session_dir).await;
+  ret
+}
+
+async fn run(mut rx: MpscReceiver<Req>, tmp_path: &Path, target: Target, flags: Vec<String>) -> anyhow::Result<()> {
   {
-    let mut dir = tmp_path.clone();
+    let mut dir = tmp_path.to_path_buf();
     dir.pop();
     tokio::fs::create_dir_all(dir).await?;
   }
 
-  println!("{:?}", tmp_path);
-
-  tokio::fs::copy(&path, &tmp_path).await?;
+  // Nothing is read from the server's disk. Every caller sends the document's
+  // contents with the first update, e.g., from the project or an LSP client's buffer.
+  tokio::fs::write(tmp_path, "").await?;
+  debug!(tmp_path = %tmp_path.display(), "Created empty copy of source");
 
   let index = CIndex::new(&CLANG, true, false);
-  let mut tu = index.parser(tmp_path).parse()?;
+  let mut args = target.args();
+  args.extend(flags);
+  let mut tu = index.parser(tmp_path).arguments(&args).parse()?;
+
+  // The latest code sent by the client, which takes precedence over the (empty) copy on disk
+  let mut code = None;
 
+  let queued = metrics::QUEUE_DEPTH.with_label_values(&["clang"]);
   while let Some(req) = rx.recv().await {
+    queued.dec();
+
     match req {
-      Req::Compile { code, tx } => {
-        if let Some(code) = code {
-          let _ = tokio::fs::write(path, code).await?;
+      Req::Compile { code: new_code, tx } => {
+        if new_code.is_some() {
+          code = new_code;
         }
 
-        tu = tu.reparse(&[])?;
+        let unsaved: Vec<Unsaved> = code.iter().map(|c| Unsaved::new(tmp_path, c)).collect();
+        tu = tu.reparse(&unsaved)?;
         let _ = tx.send(compile(&tu).await);
       },
+      Req::Complete { at, tx } => {
+        let unsaved: Vec<Unsaved> = code.iter().map(|c| Unsaved::new(tmp_path, c)).collect();
+        let _ = tx.send(Ok(complete(&tu, tmp_path, &unsaved, &at)));
+      },
+      Req::Hover { at, tx } => {
+        let _ = tx.send(Ok(hover(&tu, tmp_path, &at)));
+      }
     }
   }
 
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use super::{Session, Inc, Message};
+use super::{Session, Inc, Message, Target, Completion, Index};
 use meio::{System, Address};
 use std::path::PathBuf;
 
@@ -14,37 +14,86 @@ use lazy_static::lazy_static;
 
 use inst::Req;
 
+use crate::metrics::{self, GaugeGuard};
+use tracing::{info_span, Instrument, Span};
+
 struct ClangSession {
-  inst: MpscSender<Req>
+  inst: MpscSender<Req>,
+  target: Target,
+  _live: GaugeGuard
 }
 
 impl ClangSession {
-  pub fn new(inst: MpscSender<Req>) -> Self {
+  pub fn new(inst: MpscSender<Req>, target: Target) -> Self {
     Self {
-      inst
+      inst,
+      target,
+      _live: GaugeGuard::new(metrics::SESSIONS.with_label_values(&["clang"]))
+    }
+  }
+
+  /// Queues `req` for the instance, counting it until the instance picks it up
+  async fn send(&self, req: Req) -> bool {
+    let queued = metrics::QUEUE_DEPTH.with_label_values(&["clang"]);
+    queued.inc();
+
+    let sent = self.inst.send(req).await.is_ok();
+    if !sent {
+      queued.dec();
     }
+    sent
   }
 }
 
 #[async_trait]
 impl Session for ClangSession {
+  fn target(&self) -> &Target {
+    &self.target
+  }
+
+  fn alive(&self) -> bool {
+    !self.inst.is_closed()
+  }
+
   async fn mv(&mut self, path: PathBuf) -> anyhow::Result<()> {
     Ok(())
   }
   
   async fn update(&mut self, code: Option<String>) -> anyhow::Result<Vec<Message>> {
+    let _timer = metrics::COMPILE_SECONDS.with_label_values(&["clang", self.target.name()]).start_timer();
     let (tx, rx) = oneshot_channel();
     
-    if let Err(_) = self.inst.send(Req::Compile { code, tx }).await {
+    if !self.send(Req::Compile { code, tx }).await {
       return Err(inst::Error::Internal("Failed to update".to_string()).into())
     }
 
     Ok(rx.await??)
   }
+
+  async fn complete(&mut self, at: Index) -> anyhow::Result<Vec<Completion>> {
+    let (tx, rx) = oneshot_channel();
+
+    if !self.send(Req::Complete { at, tx }).await {
+      return Err(inst::Error::Internal("Failed to complete".to_string()).into())
+    }
+
+    Ok(rx.await??)
+  }
+
+  async fn hover(&mut self, at: Index) -> anyhow::Result<Option<String>> {
+    let (tx, rx) = oneshot_channel();
+
+    if !self.send(Req::Hover { at, tx }).await {
+      return Err(inst::Error::Internal("Failed to hover".to_string()).into())
+    }
+
+    Ok(rx.await??)
+  }
 }
 
 pub struct ClangInc {
-
+  /// Passed to clang after the target's arguments
+  flags: Vec<String>
 }
 
 lazy_static! {
@@ -61,8 +110,11 @@ lazy_static! {
 }
 
 impl ClangInc {
-  
-  
+  pub fn new(flags: Vec<String>) -> Self {
+    Self {
+      flags
+    }
+  }
 }
 
 #[async_trait]
@@ -75,18 +127,26 @@ impl Inc for ClangInc {
     "clang"
   }
 
-  async fn start_session(&self, path: PathBuf) -> anyhow::Result<Box<dyn Session>> {
+  fn languages(&self) -> &[&str] {
+    &["c", "cpp"]
+  }
+
+  async fn start_session(&self, path: PathBuf, target: Target) -> anyhow::Result<Box<dyn Session>> {
     let (tx, rx) = mpsc_channel(5);
 
+    // The session outlives the request that started it, so its span isn't nested in the request's
+    let span = info_span!(parent: None, "clang", path = %path.display(), target = target.name());
+    span.follows_from(Span::current());
+
+    let inst_target = target.clone();
+    let flags = self.flags.clone();
     std::thread::spawn::<_, anyhow::Result<()>>(move || {
       let rt = tokio::runtime::Runtime::new()?;
-      rt.block_on(inst::instance(rx, path))?;
+      rt.block_on(inst::instance(rx, path, inst_target, flags).instrument(span))?;
       Ok(())
     });
 
-    Ok(Box::new(ClangSession {
-      inst: tx
-    }))
+    Ok(Box::new(ClangSession::new(tx, target)))
   }
 }
 
@@ -5,6 +5,8 @@ use std::ffi::OsStr;
 
 use serde::{Serialize, Deserialize};
 
+use crate::config::TargetsConfig;
+
 pub mod clang;
 
 use derive_more::*;
@@ -49,11 +51,95 @@ pub struct Message {
   pub message: String
 }
 
+/// A possible completion at a point in a document
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Completion {
+  /// The text that would be inserted
+  pub label: String,
+
+  /// A short description (e.g., the documentation brief)
+  pub detail: Option<String>
+}
+
+/// The machine user code is compiled for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+  /// The architecture the server is running on
+  Host,
+  /// The KIPR Wombat controller (32-bit ARM Linux)
+  Wombat
+}
+
+impl Default for Target {
+  fn default() -> Self {
+    Self::Host
+  }
+}
+
+impl Target {
+  /// The LLVM target triple, or `None` to use the host's
+  pub fn triple(&self) -> Option<&'static str> {
+    match self {
+      Self::Host => None,
+      Self::Wombat => Some("armv7-unknown-linux-gnueabihf")
+    }
+  }
+
+  /// The target's name in the configuration and on the wire
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Host => "host",
+      Self::Wombat => "wombat"
+    }
+  }
+
+  /// The system root containing the target's headers and libraries.
+  /// The Wombat sysroot is set by `targets.wombat_sysroot` in the configuration.
+  pub fn sysroot(&self, targets: &TargetsConfig) -> Option<PathBuf> {
+    match self {
+      Self::Host => None,
+      Self::Wombat => targets.wombat_sysroot.clone()
+    }
+  }
+
+  /// Arguments that must be passed to the compiler to build for this target
+  pub fn args(&self) -> Vec<String> {
+    self.args_with(&crate::config::get().targets)
+  }
+
+  fn args_with(&self, targets: &TargetsConfig) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    if let Some(triple) = self.triple() {
+      ret.push(format!("--target={}", triple));
+    }
+
+    if let Some(sysroot) = self.sysroot(targets) {
+      ret.push(format!("--sysroot={}", sysroot.display()));
+    }
+
+    ret
+  }
+}
+
 /// A single incremental-compiler "session". 1 session = 1 file
 #[async_trait]
 pub trait Session: Send + Sync {
+  /// The target this session's diagnostics are computed for
+  fn target(&self) -> &Target;
+
+  /// Whether the session can still service requests
+  fn alive(&self) -> bool;
+
   async fn mv(&mut self, path: PathBuf) -> anyhow::Result<()>;
   async fn update(&mut self, code: Option<String>) -> anyhow::Result<Vec<Message>>;
+
+  /// Completions at `at`, using the code from the last update
+  async fn complete(&mut self, at: Index) -> anyhow::Result<Vec<Completion>>;
+
+  /// A description of the symbol at `at`, if there is one
+  async fn hover(&mut self, at: Index) -> anyhow::Result<Option<String>>;
 }
 
 /// Represents an incremental compiler for user code
@@ -62,14 +148,20 @@ pub trait Inc: Send + Sync {
   /// Name of the incremental compiler (e.g., clang)
   fn name(&self) -> &str;
 
+  /// The languages this incremental compiler understands (e.g., "c", "cpp")
+  fn languages(&self) -> &[&str];
+
   /// The file extensions this incremental compiler supports.
   /// Must not contain leading dots (e.g., "c", not ".c")
   fn extensions(&self) -> &[&OsStr];
 
-  /// Create a new session
-  async fn start_session(&self, path: PathBuf) -> anyhow::Result<Box<dyn Session>>;
+  /// Create a new session compiling for `target`
+  async fn start_session(&self, path: PathBuf, target: Target) -> anyhow::Result<Box<dyn Session>>;
 }
 
+/// Names of every incremental compiler the server is built with
+pub const INCS: &[&str] = &["clang"];
+
 #[derive(Display, Debug, Error)]
 pub enum SpawnError {
   NoInc
@@ -82,26 +174,66 @@ pub struct IncSpawner {
 }
 
 impl IncSpawner {
+  /// Creates the incremental compilers enabled in the configuration
   pub fn new() -> Self {
+    let config = crate::config::get();
+    let mut incs: Vec<Box<dyn Inc>> = Vec::new();
+
+    let clang = config.inc("clang");
+    if clang.enabled {
+      incs.push(Box::new(clang::ClangInc::new(clang.flags)));
+    }
+
     Self {
-      incs: vec! [
-        Box::new(clang::ClangInc {})
-      ]
+      incs
     }
   }
 
-  pub async fn spawn<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<Box<dyn Session>> {
+  pub fn incs(&self) -> impl Iterator<Item = &dyn Inc> {
+    self.incs.iter().map(AsRef::as_ref)
+  }
+
+  pub async fn spawn<P: AsRef<Path>>(&self, path: P, target: Target) -> anyhow::Result<Box<dyn Session>> {
     let path = path.as_ref();
-    let ext = path.extension().unwrap();
+    let ext = match path.extension() {
+      Some(ext) => ext,
+      None => return Err(SpawnError::NoInc.into())
+    };
 
     for inc in self.incs.iter() {
       if !inc.extensions().contains(&ext) {
         continue
       }
 
-      return inc.start_session(path.into()).await
+      return inc.start_session(path.into(), target).await
     }
     
     Err(SpawnError::NoInc.into())
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn targets(wombat_sysroot: Option<&str>) -> TargetsConfig {
+    TargetsConfig {
+      wombat_sysroot: wombat_sysroot.map(PathBuf::from)
+    }
+  }
+
+  #[test]
+  fn compiles_for_the_host_by_default() {
+    assert_eq!(Target::default(), Target::Host);
+    assert!(Target::Host.args_with(&targets(Some("/opt/wombat/sysroot"))).is_empty());
+  }
+
+  #[test]
+  fn cross_compiles_for_the_wombat() {
+    assert_eq!(Target::Wombat.args_with(&targets(None)), vec!["--target=armv7-unknown-linux-gnueabihf"]);
+    assert_eq!(
+      Target::Wombat.args_with(&targets(Some("/opt/wombat/sysroot"))),
+      vec!["--target=armv7-unknown-linux-gnueabihf", "--sysroot=/opt/wombat/sysroot"]
+    );
+  }
+}
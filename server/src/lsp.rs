@@ -0,0 +1,565 @@
+//! An alternative wire mode speaking JSON-RPC 2.0 with Language Server
+//! Protocol method names, so off-the-shelf LSP clients (e.g., the Monaco
+//! language client) can use ivygate's incremental compilers directly.
+//! Each open text document is backed by one `inc::Session`.
+//!
+//! Clients authenticate by passing a session token (see `LoginReq`) as
+//! `initializationOptions.token`, and name documents with
+//! `file:///<project uuid>/<path>` URIs within that user's projects.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc::unbounded_channel, watch};
+use tungstenite::Message as WsMessage;
+use uuid::Uuid;
+
+use crate::backing::{Backing, Project, UserBacking};
+use crate::inc::{Session, Message, Severity, Index, Range};
+use crate::path::ProjectPath;
+use crate::proto::{Error, ErrorCode, Encoding};
+use crate::quota::{self, Permit};
+use crate::ws::{self, Keepalive, Liveness, WebSocket, Outbox};
+use crate::config::{self, ConnectionConfig};
+use crate::http;
+use crate::shutdown::Shutdown;
+use crate::token;
+use crate::INC_SPAWNER;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// LSP: a request arrived before `initialize`
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+/// LSP: the request was valid but failed, e.g., a quota was exceeded
+const REQUEST_FAILED: i64 = -32803;
+
+#[derive(Debug, Deserialize)]
+struct RpcMsg {
+  jsonrpc: String,
+  /// Absent for notifications
+  id: Option<Value>,
+  method: String,
+  #[serde(default)]
+  params: Value
+}
+
+#[derive(Debug)]
+struct RpcError {
+  code: i64,
+  message: String,
+  data: Option<Value>
+}
+
+impl RpcError {
+  fn new<M: Into<String>>(code: i64, message: M) -> Self {
+    Self {
+      code,
+      message: message.into(),
+      data: None
+    }
+  }
+}
+
+impl From<Error> for RpcError {
+  fn from(error: Error) -> Self {
+    let code = match error.code {
+      ErrorCode::Internal => INTERNAL_ERROR,
+      ErrorCode::InvalidPath | ErrorCode::MalformedRequest => INVALID_PARAMS,
+      _ => REQUEST_FAILED
+    };
+
+    Self {
+      code,
+      message: error.message.clone(),
+      data: serde_json::to_value(&error).ok()
+    }
+  }
+}
+
+impl From<anyhow::Error> for RpcError {
+  fn from(error: anyhow::Error) -> Self {
+    Error::from(error).into()
+  }
+}
+
+fn not_initialized() -> RpcError {
+  RpcError::new(SERVER_NOT_INITIALIZED, "Initialize with a session token first")
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InitializeParams {
+  #[serde(default)]
+  initialization_options: Option<InitializationOptions>
+}
+
+#[derive(Debug, Deserialize)]
+struct InitializationOptions {
+  /// Issued by `LoginReq` on the native protocol
+  token: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct TextDocumentIdentifier {
+  uri: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentItem {
+  uri: String,
+  version: i64,
+  text: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionedTextDocumentIdentifier {
+  uri: String,
+  version: i64
+}
+
+#[derive(Debug, Deserialize)]
+struct TextDocumentContentChangeEvent {
+  text: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+  text_document: TextDocumentItem
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+  text_document: VersionedTextDocumentIdentifier,
+  content_changes: Vec<TextDocumentContentChangeEvent>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidCloseParams {
+  text_document: TextDocumentIdentifier
+}
+
+/// LSP positions are 0-based, clang's are 1-based
+#[derive(Debug, Serialize, Deserialize)]
+struct Position {
+  line: usize,
+  character: usize
+}
+
+impl From<&Index> for Position {
+  fn from(value: &Index) -> Self {
+    Self {
+      line: value.line.saturating_sub(1),
+      character: value.col.saturating_sub(1)
+    }
+  }
+}
+
+impl From<Position> for Index {
+  fn from(value: Position) -> Self {
+    Self {
+      line: value.line + 1,
+      col: value.character + 1
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TextDocumentPositionParams {
+  text_document: TextDocumentIdentifier,
+  position: Position
+}
+
+fn lsp_severity(severity: &Severity) -> u8 {
+  match severity {
+    Severity::Error => 1,
+    Severity::Warning => 2,
+    Severity::Info => 3
+  }
+}
+
+fn lsp_range(range: Option<&Range>) -> Value {
+  match range {
+    Some(range) => json!({
+      "start": Position::from(&range.start),
+      "end": Position::from(&range.end)
+    }),
+    None => json!({
+      "start": { "line": 0, "character": 0 },
+      "end": { "line": 0, "character": 0 }
+    })
+  }
+}
+
+fn lsp_diagnostic(message: &Message) -> Value {
+  json!({
+    "range": lsp_range(message.ranges.first()),
+    "severity": lsp_severity(&message.severity),
+    "source": "ivygate",
+    "message": message.message
+  })
+}
+
+/// Converts a `file:///<project uuid>/<path>` URI into the project and the path within it.
+/// Nothing else is accepted, so documents can't name files outside of a project.
+fn uri_to_path(uri: &str) -> Result<(Uuid, ProjectPath), RpcError> {
+  let unsupported = || RpcError::new(INVALID_PARAMS, format!("Expected file:///<project>/<path>, not {}", uri));
+
+  let path = uri.strip_prefix("file:///").ok_or_else(unsupported)?;
+  let path = http::percent_decode(path).ok_or_else(unsupported)?;
+  let (project, path) = path.split_once('/').ok_or_else(unsupported)?;
+  let project = Uuid::parse_str(project).map_err(|_| unsupported())?;
+
+  Ok((project, ProjectPath::new(path).map_err(Error::from)?))
+}
+
+/// The result of `initialize`
+fn capabilities() -> Value {
+  json!({
+    "capabilities": {
+      // Full document sync
+      "textDocumentSync": 1,
+      "completionProvider": { "triggerCharacters": [".", ">", ":"] },
+      "hoverProvider": true
+    },
+    "serverInfo": {
+      "name": "ivygate",
+      "version": env!("CARGO_PKG_VERSION")
+    }
+  })
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+  serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+struct Document {
+  version: i64,
+  session: Box<dyn Session>,
+
+  /// Counts `session` against the user's quota
+  _permit: Permit
+}
+
+struct LspConnection {
+  outbox: Outbox,
+  backing: Arc<dyn Backing>,
+
+  /// Set by `initialize` once the client's session token checks out
+  user: Option<Box<dyn UserBacking>>,
+  ident: Option<String>,
+
+  /// Projects with open documents, opened through `user` so its access rules apply
+  projects: HashMap<Uuid, Box<dyn Project>>,
+  documents: HashMap<String, Document>,
+  shutdown: bool,
+
+  /// The connection's span, which records the user once they initialize
+  span: Span
+}
+
+impl LspConnection {
+  fn send(&mut self, value: Value) -> anyhow::Result<()> {
+    Ok(self.outbox.send(WsMessage::Text(value.to_string()))?)
+  }
+
+  fn close(&mut self) -> anyhow::Result<()> {
+    Ok(self.outbox.send(WsMessage::Close(None))?)
+  }
+
+  fn respond(&mut self, id: Value, result: Result<Value, RpcError>) -> anyhow::Result<()> {
+    self.send(match result {
+      Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+      Err(e) => json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": e.code, "message": e.message, "data": e.data }
+      })
+    })
+  }
+
+  fn ident(&self) -> Result<String, RpcError> {
+    self.ident.clone().ok_or_else(not_initialized)
+  }
+
+  /// Logs in as the user the session token in `initializationOptions` was issued to
+  async fn initialize(&mut self, InitializeParams { initialization_options }: InitializeParams) -> Result<(), RpcError> {
+    if self.user.is_some() {
+      return Err(RpcError::new(INVALID_REQUEST, "Already initialized"));
+    }
+
+    let token = initialization_options.and_then(|options| options.token)
+      .ok_or_else(|| Error::new(ErrorCode::AuthFailed, "A session token is required in initializationOptions"))?;
+    let claims = token::verify(&token).map_err(|e| Error::new(ErrorCode::AuthFailed, e.to_string()))?;
+
+    self.user = Some(self.backing.resume(claims.ident.clone()).await?);
+    self.ident = Some(claims.ident.to_string());
+    self.span.record("user", field::display(&claims.ident));
+    Ok(())
+  }
+
+  /// Opens `uuid` through the user's backing the first time one of its documents is opened
+  async fn project(&mut self, uuid: Uuid) -> Result<&dyn Project, RpcError> {
+    if !self.projects.contains_key(&uuid) {
+      let user = self.user.as_mut().ok_or_else(not_initialized)?;
+      let project = user.open_project(uuid).await?;
+      self.projects.insert(uuid, project);
+    }
+
+    Ok(self.projects[&uuid].as_ref())
+  }
+
+  /// Spawns a session for a document, within the same limits as `Connection::start_session`
+  async fn open(&mut self, uri: &str, version: i64) -> Result<(), RpcError> {
+    let (uuid, path) = uri_to_path(uri)?;
+    let ident = self.ident()?;
+
+    let max_sessions = config::get().limits.max_sessions;
+    if self.documents.len() >= max_sessions {
+      let message = format!("At most {} compiler sessions are allowed per connection", max_sessions);
+      return Err(Error::quota_exceeded(message).with_path(&path).into());
+    }
+    let permit = quota::session(&ident).map_err(|e| e.with_path(&path))?;
+
+    let target = self.project(uuid).await?.target().await?;
+
+    // Keyed by project like native sessions, so the same path in two projects doesn't collide
+    let session_path = PathBuf::from(uuid.to_string()).join(&path);
+    let session = INC_SPAWNER.spawn(&session_path, target).await
+      .map_err(|e| Error::from(e).with_path(&path))?;
+
+    self.documents.insert(uri.to_string(), Document {
+      version,
+      session,
+      _permit: permit
+    });
+    Ok(())
+  }
+
+  /// Ends every session and closes the projects they were in
+  async fn close_all(&mut self) {
+    self.documents.clear();
+
+    if let Some(user) = self.user.as_mut() {
+      for (uuid, _) in self.projects.drain() {
+        let _ = user.close_project(uuid).await;
+      }
+    }
+  }
+
+  /// Recompiles a document and pushes its diagnostics to the client
+  async fn publish(&mut self, uri: &str, code: Option<String>) -> Result<(), RpcError> {
+    let ident = self.ident()?;
+    let document = self.documents.get_mut(uri)
+      .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} is not open", uri)))?;
+
+    let _build = quota::build(&ident)?;
+    let messages = document.session.update(code).await?;
+    let version = document.version;
+
+    self.send(json!({
+      "jsonrpc": "2.0",
+      "method": "textDocument/publishDiagnostics",
+      "params": {
+        "uri": uri,
+        "version": version,
+        "diagnostics": messages.iter().map(lsp_diagnostic).collect::<Vec<_>>()
+      }
+    })).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+  }
+
+  fn document(&mut self, uri: &str) -> Result<&mut Document, RpcError> {
+    self.documents.get_mut(uri)
+      .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{} is not open", uri)))
+  }
+
+  async fn dispatch(&mut self, method: &str, params_value: Value) -> Result<Value, RpcError> {
+    if self.user.is_none() && method != "initialize" && method != "shutdown" {
+      return Err(not_initialized());
+    }
+
+    match method {
+      "initialize" => {
+        self.initialize(params(params_value)?).await?;
+        Ok(capabilities())
+      },
+      "initialized" => Ok(Value::Null),
+      "shutdown" => {
+        self.shutdown = true;
+        self.close_all().await;
+        Ok(Value::Null)
+      },
+      "textDocument/didOpen" => {
+        let DidOpenParams { text_document } = params(params_value)?;
+        self.open(&text_document.uri, text_document.version).await?;
+        self.publish(&text_document.uri, Some(text_document.text)).await?;
+        Ok(Value::Null)
+      },
+      "textDocument/didChange" => {
+        let DidChangeParams { text_document, content_changes } = params(params_value)?;
+
+        // We only advertise full sync, so the last change holds the whole document
+        let text = content_changes.into_iter().last().map(|c| c.text);
+        self.document(&text_document.uri)?.version = text_document.version;
+        self.publish(&text_document.uri, text).await?;
+        Ok(Value::Null)
+      },
+      "textDocument/didClose" => {
+        let DidCloseParams { text_document } = params(params_value)?;
+        self.documents.remove(&text_document.uri);
+        Ok(Value::Null)
+      },
+      "textDocument/completion" => {
+        let TextDocumentPositionParams { text_document, position } = params(params_value)?;
+        let completions = self.document(&text_document.uri)?.session.complete(position.into()).await?;
+
+        Ok(json!(completions.into_iter().map(|c| json!({
+          "label": c.label,
+          "detail": c.detail
+        })).collect::<Vec<_>>()))
+      },
+      "textDocument/hover" => {
+        let TextDocumentPositionParams { text_document, position } = params(params_value)?;
+        let hover = self.document(&text_document.uri)?.session.hover(position.into()).await?;
+
+        Ok(match hover {
+          Some(value) => json!({ "contents": { "kind": "markdown", "value": value } }),
+          None => Value::Null
+        })
+      },
+      _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+    }
+  }
+}
+
+pub async fn accept_connection(websocket: WebSocket, config: ConnectionConfig, backing: Arc<dyn Backing>, shutdown: Shutdown) -> anyhow::Result<()> {
+  // Diagnostics are published as JSON-RPC notifications on the outbox,
+  // so there's no use for protocol notifications or other encodings.
+  let (_, notifications) = unbounded_channel();
+  let (_, encoding) = watch::channel(Encoding::Json);
+  let (stream, outbox, writer) = ws::split(websocket, notifications, encoding);
+
+  let mut conn = LspConnection {
+    outbox,
+    backing,
+    user: None,
+    ident: None,
+    projects: HashMap::new(),
+    documents: HashMap::new(),
+    shutdown: false,
+    span: Span::current()
+  };
+
+  let ret = read_loop(stream, &config, &mut conn, shutdown).await;
+
+  conn.close_all().await;
+  drop(conn);
+  let _ = writer.await;
+  ret
+}
+
+async fn read_loop(
+  mut stream: futures_util::stream::SplitStream<WebSocket>,
+  config: &ConnectionConfig,
+  conn: &mut LspConnection,
+  mut shutdown: Shutdown
+) -> anyhow::Result<()> {
+  let mut bad_frames = 0usize;
+  let mut keepalive = Keepalive::new(config);
+
+  loop {
+    let idle_timeout = match conn.user {
+      Some(_) => config.idle_timeout,
+      None => config.login_timeout
+    };
+
+    let msg = tokio::select! {
+      msg = stream.next() => match msg {
+        Some(msg) => msg?,
+        None => break
+      },
+      liveness = keepalive.next(idle_timeout) => match liveness {
+        Liveness::Ping => {
+          conn.outbox.send(WsMessage::Ping(Vec::new()))?;
+          continue
+        },
+        Liveness::Dead => {
+          debug!("Client stopped responding to pings");
+          return Ok(());
+        },
+        Liveness::Idle => {
+          debug!("Closing idle connection");
+          conn.outbox.send(ws::idle())?;
+          return Ok(());
+        }
+      },
+      _ = shutdown.wait() => {
+        conn.outbox.send(ws::going_away())?;
+        return Ok(());
+      }
+    };
+    keepalive.saw(&msg);
+
+    let text = match &msg {
+      WsMessage::Text(text) => text,
+      WsMessage::Close(_) => return Ok(()),
+      _ => continue
+    };
+
+    let rpc = match serde_json::from_str::<RpcMsg>(text) {
+      Ok(rpc) if rpc.jsonrpc == "2.0" => rpc,
+      parsed => {
+        let (code, message) = match parsed {
+          Ok(_) => (INVALID_REQUEST, "Only JSON-RPC 2.0 is supported".to_string()),
+          Err(e) => (PARSE_ERROR, e.to_string())
+        };
+
+        bad_frames += 1;
+        warn!("Malformed JSON-RPC frame ({} of {}): {}", bad_frames, config.max_bad_frames, message);
+        conn.respond(Value::Null, Err(RpcError::new(code, message)))?;
+
+        if bad_frames >= config.max_bad_frames {
+          conn.close()?;
+          return Ok(());
+        }
+
+        continue
+      }
+    };
+
+    if rpc.method == "exit" {
+      conn.close()?;
+      return Ok(());
+    }
+
+    let result = if conn.shutdown && rpc.method != "shutdown" {
+      Err(RpcError::new(INVALID_REQUEST, "Server is shutting down"))
+    } else {
+      let span = info_span!("rpc", method = rpc.method.as_str());
+      conn.dispatch(&rpc.method, rpc.params).instrument(span).await
+    };
+
+    match rpc.id {
+      Some(id) => conn.respond(id, result)?,
+      // Notifications have no reply, so errors can only be logged
+      None => if let Err(e) = result {
+        warn!("{} failed: {}", rpc.method, e.message);
+      }
+    }
+  }
+
+  Ok(())
+}
@@ -1,9 +1,10 @@
-use std::{env, io::Error};
+use std::time::Duration;
 
-use futures_util::StreamExt;
-use log::info;
+use anyhow::Context;
+use clap::Parser;
 use tokio::net::{TcpListener};
-use std::net::TcpStream;
+use tracing::{info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
 mod inc;
 mod fs;
@@ -11,109 +12,132 @@ mod proto;
 mod backing;
 mod test;
 mod db;
+mod lsp;
+mod conn;
+mod codec;
+mod ws;
+mod tls;
+mod config;
+mod shutdown;
+mod http;
+mod assets;
+mod metrics;
+mod quota;
+mod token;
+mod path;
 
 use proto::*;
 
-use inc::{IncSpawner, Session};
-use std::collections::HashMap;
-
-
-
-use tungstenite::{server::accept, WebSocket, Message};
+use inc::IncSpawner;
+use backing::{Backing, MeteredBacking, SimpleBacking};
+use config::{Args, BackingConfig, Config, LogFormat};
+use shutdown::Coordinator;
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
 
-use std::io::{Read, Write};
+use tokio::sync::mpsc::UnboundedSender;
 
 lazy_static! {
   static ref INC_SPAWNER: IncSpawner = IncSpawner::new();
 }
 
-trait WebSocketExt {
-  fn write_res(&mut self, res: Res) -> tungstenite::Result<()>;
-}
+/// Queues notifications to be pushed to a connected client
+pub type Notifier = UnboundedSender<Notification>;
 
-impl<T: Read + Write> WebSocketExt for WebSocket<T> {
-  fn write_res(&mut self, res: Res) -> tungstenite::Result<()> {
-    self.write_message(res.as_ws_msg())
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+  let config = Config::load(Args::parse())?;
+
+  let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(config.log_level.to_string()));
+  match config.log_format {
+    LogFormat::Text => tracing_subscriber::fmt().with_env_filter(filter).init(),
+    LogFormat::Json => tracing_subscriber::fmt().json().with_current_span(true).with_span_list(true).with_env_filter(filter).init()
   }
-}
 
-async fn accept_connection(mut websocket: WebSocket<TcpStream>) -> anyhow::Result<()> {
-  let mut handle_iter = 0u64;
-  let mut files = HashMap::new();
-  
-  loop {
-    let msg = websocket.read_message()?;
+  config::init(config);
+  let config = config::get();
 
-    // Get text from message (could also be binary). If we fail, silently ignore.
-    let text = match msg.to_text() {
-      Ok(text) => text,
-      _ => continue
-    };
+  let backing: Arc<dyn Backing> = match &config.backing {
+    BackingConfig::Simple { root } => {
+      tokio::fs::create_dir_all(root).await
+        .with_context(|| format!("Failed to create storage root {}", root.display()))?;
+      Arc::new(MeteredBacking::new(SimpleBacking::new(root.clone())))
+    }
+  };
 
-    // Parse request from the JSON. If we fail, silently ignore.
-    let req = match serde_json::from_str::<Req>(text) {
-      Ok(req) => req,
-      _ => continue
-    };
-    
-    let _ = match &req.kind {
-      ReqKind::Open(OpenReq { path }) => {
-        // Open a session and store it
-        match INC_SPAWNER.spawn(path).await {
-          Ok(session) => {
-            handle_iter += 1;
-            files.insert(handle_iter, session);
-            websocket.write_res(req.reply(OpenRes::success(handle_iter, )))
-          },
-          Err(e) => websocket.write_res(req.reply(OpenRes::error(e)))
-        }
-      },
-      ReqKind::Update(UpdateReq { handle, code }) => {
-        match files.get_mut(handle) {
-          Some(session) => match session.update(code.clone()).await {
-            Ok(messages) => websocket.write_res(req.reply(UpdateRes::success(messages))),
-            Err(e) => websocket.write_res(req.reply(UpdateRes::error(e)))
-          },
-          None => websocket.write_res(req.reply(UpdateRes::error("No such file")))
+  let tmp_dir = fs::tmp_dir();
+  tokio::fs::create_dir_all(&tmp_dir).await
+    .with_context(|| format!("Failed to create temporary directory {}", tmp_dir.display()))?;
+
+  let tls = match &config.tls {
+    Some(tls_config) => Some(tls::Tls::new(tls_config.clone()).context("Failed to load TLS certificate")?),
+    None => None
+  };
+
+  let listener = TcpListener::bind(config.bind).await
+    .with_context(|| format!("Failed to bind {}", config.bind))?;
+  info!("Listening on: {}://{}", if tls.is_some() { "https" } else { "http" }, config.bind);
+
+  let coordinator = Coordinator::new();
+  let stop = shutdown::signal();
+  tokio::pin!(stop);
+
+  loop {
+    let (stream, addr) = tokio::select! {
+      accepted = listener.accept() => match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+          warn!("Failed to accept connection: {}", e);
+          continue
         }
       },
-      ReqKind::Close(CloseReq { handle }) => {
-        match files.remove(handle) {
-          Some(_) => websocket.write_res(req.reply(CloseRes::success())),
-          None => websocket.write_res(req.reply(UpdateRes::error("No such file")))
-        }
-      }
+      _ = &mut stop => break
     };
-  }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let shutdown = coordinator.subscribe();
+    let config = config.connection.clone();
+    let backing = backing.clone();
+    let tls = tls.clone();
+
+    tokio::spawn(async move {
+      let stream: Box<dyn ws::Stream> = match tls {
+        Some(tls) => match tls.acceptor().accept(stream).await {
+          Ok(stream) => Box::new(stream),
+          Err(e) => {
+            warn!("TLS handshake failed: {}", e);
+            return;
+          }
+        },
+        None => Box::new(stream)
+      };
+
+      if let Err(e) = http::serve(stream, config, backing, shutdown).await {
+        warn!("Connection failed: {}", e);
+      }
+    }.instrument(info_span!("conn", %addr)));
+  }
 
-  let _ = env_logger::try_init();
-  let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8000".to_string());
+  drop(listener);
+  info!("Shutting down, waiting up to {}s for connections to close", config.shutdown_timeout);
 
-  // Create the event loop and TCP listener we'll accept connections on.
-  let try_socket = TcpListener::bind(&addr).await;
-  let listener = try_socket.expect("Failed to bind");
-  info!("Listening on: {}", addr);
+  let deadline = Duration::from_secs(config.shutdown_timeout);
+  let graceful = tokio::select! {
+    drained = tokio::time::timeout(deadline, coordinator.shutdown()) => drained.is_ok(),
+    _ = shutdown::signal() => false
+  };
 
-  let mut session = INC_SPAWNER.spawn("C:\\Users\\Semio\\ivygate\\test.cpp").await.unwrap();
-  println!("{:?}", session.update(None).await?);
+  // Every session has been dropped (or is being abandoned), so no clang instance needs its copy.
+  // Only our own folder goes: `tmp_dir` itself may be shared, e.g., `/tmp`.
+  if let Err(e) = std::fs::remove_dir_all(&tmp_dir) {
+    warn!("Failed to remove {}: {}", tmp_dir.display(), e);
+  }
 
-  loop {
-    let (stream, addr) = listener.accept().await?;
-    let stream = stream.into_std()?;
-    stream.set_nonblocking(false)?;
-    let websocket = accept(stream)?;
-    
-    std::thread::spawn(move || {
-      let rt = tokio::runtime::Runtime::new().unwrap();
-      rt.block_on(accept_connection(websocket)).unwrap();
-    });
+  if !graceful {
+    warn!("Connections didn't close in time, exiting anyway");
+    std::process::exit(1);
   }
 
+  info!("Shut down cleanly");
   Ok(())
 }
@@ -0,0 +1,90 @@
+//! Prometheus metrics, exposed at `/metrics` on the HTTP port
+
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use prometheus::{
+  register_histogram_vec, register_int_counter_vec, register_int_gauge_vec,
+  Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder
+};
+
+use crate::proto::{ReqKind, ResKind};
+
+lazy_static! {
+  /// Open websocket connections by wire mode
+  pub static ref CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+    "ivygate_connections",
+    "Open websocket connections",
+    &["mode"]
+  ).unwrap();
+
+  /// Live compiler sessions by incremental compiler
+  pub static ref SESSIONS: IntGaugeVec = register_int_gauge_vec!(
+    "ivygate_sessions",
+    "Live incremental compiler sessions",
+    &["inc"]
+  ).unwrap();
+
+  /// Time taken by `Session::update`, including time spent queued
+  pub static ref COMPILE_SECONDS: HistogramVec = register_histogram_vec!(
+    "ivygate_compile_seconds",
+    "Time to compile a file and report its diagnostics",
+    &["inc", "target"],
+    vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+  ).unwrap();
+
+  /// Requests sent to compiler instances that haven't been picked up yet
+  pub static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+    "ivygate_inc_queue_depth",
+    "Requests waiting for an incremental compiler instance",
+    &["inc"]
+  ).unwrap();
+
+  static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+    "ivygate_requests_total",
+    "Requests serviced, by request type and error code (\"ok\" on success)",
+    &["kind", "code"]
+  ).unwrap();
+
+  static ref BACKING_SECONDS: HistogramVec = register_histogram_vec!(
+    "ivygate_backing_seconds",
+    "Time taken by backing operations",
+    &["op"]
+  ).unwrap();
+}
+
+/// Increments a gauge until dropped
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+  pub fn new(gauge: IntGauge) -> Self {
+    gauge.inc();
+    Self(gauge)
+  }
+}
+
+impl Drop for GaugeGuard {
+  fn drop(&mut self) {
+    self.0.dec();
+  }
+}
+
+/// Counts a serviced request
+pub fn request(req: &ReqKind, res: &ResKind) {
+  let code = res.error().map(|e| e.code.name()).unwrap_or("ok");
+  REQUESTS.with_label_values(&[req.name(), code]).inc();
+}
+
+/// Times a backing operation
+pub async fn backing<T, F: Future<Output = T>>(op: &str, f: F) -> T {
+  let _timer = BACKING_SECONDS.with_label_values(&[op]).start_timer();
+  f.await
+}
+
+/// Every registered metric in the Prometheus text format
+pub fn gather() -> anyhow::Result<(String, Vec<u8>)> {
+  let encoder = TextEncoder::new();
+  let mut ret = Vec::new();
+  encoder.encode(&prometheus::gather(), &mut ret)?;
+  Ok((encoder.format_type().to_string(), ret))
+}
@@ -0,0 +1,238 @@
+//! Paths supplied by clients, confined to the project they name.
+//!
+//! A `ProjectPath` can only be constructed from a relative path made of plain
+//! names, so it can't be absolute or climb out with `..`. Symlinks are checked
+//! when the path is resolved against a project's root on disk, since that's the
+//! only time they can be seen.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+
+use derive_more::*;
+use serde::{Serialize, Deserialize};
+
+#[derive(Display, Debug, Error)]
+pub enum PathError {
+  #[display(fmt = "A path within the project is required")]
+  Empty,
+  #[display(fmt = "{} is not relative to the project", "_0.display()")]
+  Absolute(#[error(ignore)] PathBuf),
+  #[display(fmt = "{} must not contain \"..\"", "_0.display()")]
+  ParentDir(#[error(ignore)] PathBuf),
+  #[display(fmt = "{} leads outside of the project", "_0.display()")]
+  Escapes(#[error(ignore)] PathBuf),
+  #[display(fmt = "{} is reserved for the server's own use", "_0.display()")]
+  Reserved(#[error(ignore)] PathBuf)
+}
+
+/// A file or folder in a project, relative to the project's root
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct ProjectPath(PathBuf);
+
+impl ProjectPath {
+  /// Validates `path`, dropping any `.` components
+  pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, PathError> {
+    let path = path.as_ref();
+    let mut ret = PathBuf::new();
+
+    for component in path.components() {
+      match component {
+        Component::Normal(name) => ret.push(name),
+        Component::CurDir => {},
+        Component::ParentDir => return Err(PathError::ParentDir(path.to_path_buf())),
+        Component::RootDir | Component::Prefix(_) => return Err(PathError::Absolute(path.to_path_buf()))
+      }
+    }
+
+    if ret.as_os_str().is_empty() {
+      return Err(PathError::Empty);
+    }
+
+    Ok(Self(ret))
+  }
+
+  /// Where this path is under `root` on disk. Fails if a symlink along the way
+  /// leads outside of `root`. Components that don't exist yet are fine.
+  pub async fn resolve(&self, root: &Path) -> anyhow::Result<PathBuf> {
+    let canonical_root = tokio::fs::canonicalize(root).await?;
+    let mut ret = root.to_path_buf();
+
+    for component in self.0.components() {
+      ret.push(component);
+
+      let metadata = match tokio::fs::symlink_metadata(&ret).await {
+        Ok(metadata) => metadata,
+        // Nothing below a missing component can exist either
+        Err(e) if e.kind() == ErrorKind::NotFound => break,
+        Err(e) => return Err(e.into())
+      };
+
+      if metadata.file_type().is_symlink() {
+        let target = match tokio::fs::canonicalize(&ret).await {
+          Ok(target) => target,
+          // Dangling, so where it points can't be checked
+          Err(e) if e.kind() == ErrorKind::NotFound => return Err(PathError::Escapes(self.0.clone()).into()),
+          Err(e) => return Err(e.into())
+        };
+
+        if !target.starts_with(&canonical_root) {
+          return Err(PathError::Escapes(self.0.clone()).into());
+        }
+      }
+    }
+
+    Ok(ret)
+  }
+}
+
+impl TryFrom<PathBuf> for ProjectPath {
+  type Error = PathError;
+
+  fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
+    Self::new(path)
+  }
+}
+
+impl From<ProjectPath> for PathBuf {
+  fn from(path: ProjectPath) -> Self {
+    path.0
+  }
+}
+
+impl From<&ProjectPath> for PathBuf {
+  fn from(path: &ProjectPath) -> Self {
+    path.0.clone()
+  }
+}
+
+impl Deref for ProjectPath {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for ProjectPath {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl fmt::Display for ProjectPath {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0.display())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A scratch project root, removed when dropped
+  struct Scratch(PathBuf);
+
+  impl Scratch {
+    fn new() -> Self {
+      let path = std::env::temp_dir().join(format!("ivygate-path-{}", uuid::Uuid::new_v4()));
+      std::fs::create_dir_all(path.join("project")).unwrap();
+      Self(path)
+    }
+
+    fn root(&self) -> PathBuf {
+      self.0.join("project")
+    }
+  }
+
+  impl Drop for Scratch {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  #[test]
+  fn accepts_relative_paths() {
+    assert_eq!(ProjectPath::new("src/main.c").unwrap().as_ref(), Path::new("src/main.c"));
+    assert_eq!(ProjectPath::new("./src/./main.c").unwrap().as_ref(), Path::new("src/main.c"));
+  }
+
+  #[test]
+  fn rejects_empty_paths() {
+    assert!(matches!(ProjectPath::new(""), Err(PathError::Empty)));
+    assert!(matches!(ProjectPath::new("."), Err(PathError::Empty)));
+  }
+
+  #[test]
+  fn rejects_absolute_paths() {
+    assert!(matches!(ProjectPath::new("/etc/passwd"), Err(PathError::Absolute(_))));
+    assert!(matches!(ProjectPath::new("//etc/passwd"), Err(PathError::Absolute(_))));
+  }
+
+  #[cfg(windows)]
+  #[test]
+  fn rejects_windows_prefixes() {
+    assert!(matches!(ProjectPath::new("C:\\Windows"), Err(PathError::Absolute(_))));
+    assert!(matches!(ProjectPath::new("C:Windows"), Err(PathError::Absolute(_))));
+    assert!(matches!(ProjectPath::new("\\\\server\\share\\file"), Err(PathError::Absolute(_))));
+  }
+
+  #[test]
+  fn rejects_parent_components() {
+    assert!(matches!(ProjectPath::new("../../etc"), Err(PathError::ParentDir(_))));
+    assert!(matches!(ProjectPath::new("src/../../other-project/main.c"), Err(PathError::ParentDir(_))));
+    // Even when it would stay inside the project
+    assert!(matches!(ProjectPath::new("src/../main.c"), Err(PathError::ParentDir(_))));
+  }
+
+  #[test]
+  fn rejects_escapes_when_deserialized() {
+    assert!(serde_json::from_str::<ProjectPath>("\"../../etc/passwd\"").is_err());
+    assert!(serde_json::from_str::<ProjectPath>("\"/etc/passwd\"").is_err());
+    assert_eq!(serde_json::from_str::<ProjectPath>("\"src/main.c\"").unwrap().as_ref(), Path::new("src/main.c"));
+  }
+
+  #[tokio::test]
+  async fn resolves_under_the_root() {
+    let scratch = Scratch::new();
+    let root = scratch.root();
+
+    let path = ProjectPath::new("src/new/main.c").unwrap();
+    assert_eq!(path.resolve(&root).await.unwrap(), root.join("src/new/main.c"));
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn rejects_symlinks_out_of_the_project() {
+    use std::os::unix::fs::symlink;
+
+    let scratch = Scratch::new();
+    let root = scratch.root();
+    std::fs::write(scratch.0.join("secret"), "").unwrap();
+    symlink(&scratch.0, root.join("outside")).unwrap();
+    symlink(scratch.0.join("secret"), root.join("secret")).unwrap();
+    symlink(scratch.0.join("missing"), root.join("dangling")).unwrap();
+
+    for path in &["outside", "outside/secret", "outside/project/main.c", "secret", "dangling"] {
+      let err = ProjectPath::new(path).unwrap().resolve(&root).await.unwrap_err();
+      assert!(matches!(err.downcast_ref::<PathError>(), Some(PathError::Escapes(_))), "{} resolved", path);
+    }
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn allows_symlinks_within_the_project() {
+    use std::os::unix::fs::symlink;
+
+    let scratch = Scratch::new();
+    let root = scratch.root();
+    std::fs::create_dir(root.join("src")).unwrap();
+    symlink(root.join("src"), root.join("link")).unwrap();
+
+    let path = ProjectPath::new("link/main.c").unwrap();
+    assert_eq!(path.resolve(&root).await.unwrap(), root.join("link/main.c"));
+  }
+}
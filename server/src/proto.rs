@@ -1,15 +1,212 @@
 use serde::{Serialize, Deserialize};
 
 use std::path::PathBuf;
-use crate::inc::Message;
+use crate::inc::{Message, Target, SpawnError};
+use crate::fs::{Contents, EntryInfo, ReadError};
+use crate::token::TokenError;
+use crate::path::{PathError, ProjectPath};
+pub use crate::codec::Encoding;
 
 use derive_more::*;
 
-use std::fmt::Display;
+use std::io::ErrorKind;
 
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Serializes a `Uuid` as a hyphenated string in every encoding. On its own, `uuid`
+/// only does that for JSON and sends 16 raw bytes in MessagePack and CBOR.
+mod uuid_string {
+  use serde::{Deserialize, Deserializer, Serializer};
+  use uuid::Uuid;
+
+  pub fn serialize<S: Serializer>(uuid: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(uuid)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+    let uuid = String::deserialize(deserializer)?;
+    Uuid::parse_str(&uuid).map_err(serde::de::Error::custom)
+  }
+}
+
+/// The protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest client protocol version this server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features implemented by this server
+pub const FEATURES: &[&str] = &["notifications", "targets", "lsp", "batch", "msgpack", "cbor", "tokens", "reattach", "listing", "binary", "watch"];
+
+/// Whether a peer speaking protocol `version` can talk to this server
+pub fn compatible(version: u32) -> bool {
+  version >= MIN_PROTOCOL_VERSION && version <= PROTOCOL_VERSION
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+  /// Languages that can be compiled (e.g., "c", "cpp")
+  #[serde(default)]
+  pub languages: Vec<String>,
+
+  /// Names of the available incremental compilers
+  #[serde(default)]
+  pub incs: Vec<String>,
+
+  /// Optional protocol features (see `FEATURES`)
+  #[serde(default)]
+  pub features: Vec<String>
+}
+
+/// Machine-readable classification of an `Error`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  /// No incremental compiler supports the file
+  NoInc,
+  /// The path is neither a file nor a folder
+  UnsupportedEntry,
+  /// The file, folder or project doesn't exist
+  NotFound,
+  /// The session token is invalid, expired or revoked
+  AuthFailed,
+  /// The handle doesn't refer to an open file
+  NoSuchHandle,
+  /// The request requires a successful login first
+  NotLoggedIn,
+  /// The project must be opened before its files can be used
+  ProjectNotOpen,
+  /// The client's protocol version isn't supported
+  IncompatibleVersion,
+  /// The connection didn't start with a `HelloReq`
+  NoHandshake,
+  /// A binary frame was sent without negotiating a binary encoding
+  MalformedFrame,
+  /// A frame's contents couldn't be decoded as a request
+  MalformedRequest,
+  /// A per-user or per-connection limit was reached. The message says which.
+  QuotaExceeded,
+  /// The path is absolute, contains `..` or leads outside of the project
+  InvalidPath,
+  /// Anything else. The message is the only useful information.
+  Internal
+}
+
+impl ErrorCode {
+  /// The code as it appears on the wire
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::NoInc => "no_inc",
+      Self::UnsupportedEntry => "unsupported_entry",
+      Self::NotFound => "not_found",
+      Self::AuthFailed => "auth_failed",
+      Self::NoSuchHandle => "no_such_handle",
+      Self::NotLoggedIn => "not_logged_in",
+      Self::ProjectNotOpen => "project_not_open",
+      Self::IncompatibleVersion => "incompatible_version",
+      Self::NoHandshake => "no_handshake",
+      Self::MalformedFrame => "malformed_frame",
+      Self::MalformedRequest => "malformed_request",
+      Self::QuotaExceeded => "quota_exceeded",
+      Self::InvalidPath => "invalid_path",
+      Self::Internal => "internal"
+    }
+  }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ErrorDetails {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub path: Option<PathBuf>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub handle: Option<u64>
+}
+
+/// An error reported to the client in a response
+#[derive(Debug, Display, Serialize, Deserialize)]
+#[display(fmt = "{}", message)]
+pub struct Error {
+  pub code: ErrorCode,
+
+  /// Human-readable description of the error
+  pub message: String,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub details: Option<ErrorDetails>
+}
+
+impl Error {
+  pub fn new<M: Into<String>>(code: ErrorCode, message: M) -> Self {
+    Self {
+      code,
+      message: message.into(),
+      details: None
+    }
+  }
+
+  pub fn no_such_handle(handle: u64) -> Self {
+    Self::new(ErrorCode::NoSuchHandle, "No such file").with_handle(handle)
+  }
+
+  pub fn not_logged_in() -> Self {
+    Self::new(ErrorCode::NotLoggedIn, "Not logged in")
+  }
+
+  pub fn project_not_open(uuid: Uuid) -> Self {
+    Self::new(ErrorCode::ProjectNotOpen, format!("Project {} is not open", uuid))
+  }
+
+  pub fn quota_exceeded<M: Into<String>>(message: M) -> Self {
+    Self::new(ErrorCode::QuotaExceeded, message)
+  }
+
+  pub fn with_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+    self.details.get_or_insert_with(ErrorDetails::default).path = Some(path.into());
+    self
+  }
+
+  pub fn with_handle(mut self, handle: u64) -> Self {
+    self.details.get_or_insert_with(ErrorDetails::default).handle = Some(handle);
+    self
+  }
+}
+
+impl From<PathError> for Error {
+  fn from(error: PathError) -> Self {
+    Self::new(ErrorCode::InvalidPath, error.to_string())
+  }
+}
+
+impl From<anyhow::Error> for Error {
+  fn from(error: anyhow::Error) -> Self {
+    let message = error.to_string();
+
+    for cause in error.chain() {
+      let code = if let Some(SpawnError::NoInc) = cause.downcast_ref::<SpawnError>() {
+        ErrorCode::NoInc
+      } else if let Some(ReadError::UnsupportedEntry) = cause.downcast_ref::<ReadError>() {
+        ErrorCode::UnsupportedEntry
+      } else if cause.is::<TokenError>() {
+        ErrorCode::AuthFailed
+      } else if cause.is::<PathError>() {
+        ErrorCode::InvalidPath
+      } else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+        match e.kind() {
+          ErrorKind::NotFound => ErrorCode::NotFound,
+          _ => continue
+        }
+      } else {
+        continue
+      };
+
+      return Self::new(code, message);
+    }
+
+    Self::new(ErrorCode::Internal, message)
+  }
+}
+
+#[derive(Debug, Clone, Display, Serialize, Deserialize)]
 pub enum Ident {
   Username(String),
   Email(String)
@@ -25,7 +222,7 @@ impl Ident {
   }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
   pub ident: Ident,
   pub password: String
@@ -33,13 +230,35 @@ pub struct User {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProjectBrief {
+  #[serde(with = "uuid_string")]
   pub uuid: Uuid,
   pub name: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloReq {
+  pub version: u32,
+
+  /// Encodings the client can use for subsequent frames, most preferred first
+  #[serde(default)]
+  pub encodings: Vec<Encoding>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginReq {
-  user: User
+  pub user: User
+}
+
+/// Logs in as the user a session token was issued to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeReq {
+  pub token: String
+}
+
+/// Revokes the connection's session token and forgets the user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutReq {
+
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,36 +273,59 @@ pub struct CreateProjectReq {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteProjectReq {
+  #[serde(with = "uuid_string")]
   pub uuid: Uuid
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenProjectReq {
+  #[serde(with = "uuid_string")]
   pub uuid: Uuid,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CloseProjectReq {
+  #[serde(with = "uuid_string")]
   pub uuid: Uuid,
 }
 
+/// Lists a project's files and folders without their contents
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListFilesReq {
+  #[serde(with = "uuid_string")]
+  pub project: Uuid,
+
+  /// The folder to list, relative to the project. The project's root if absent.
+  #[serde(default)]
+  pub path: Option<ProjectPath>,
+
+  /// How many levels to descend, e.g., 1 for just the folder's own entries.
+  /// The whole tree if absent.
+  #[serde(default)]
+  pub depth: Option<usize>
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateFileReq {
+  #[serde(with = "uuid_string")]
   pub project: Uuid,
-  pub path: PathBuf,
-  pub contents: Option<String>
+  pub path: ProjectPath,
+  /// Text as a string, binary data as bytes (or `{ "base64": "..." }` in JSON). Empty if absent.
+  pub contents: Option<Contents>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteFileReq {
+  #[serde(with = "uuid_string")]
   pub project: Uuid,
-  pub path: PathBuf,
+  pub path: ProjectPath,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenFileReq {
+  #[serde(with = "uuid_string")]
   pub project: Uuid,
-  pub path: PathBuf,
+  pub path: ProjectPath,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -100,9 +342,15 @@ pub struct CloseFileReq {
 #[derive(Debug, From, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ReqKind {
+  #[from]
+  Hello(HelloReq),
   #[from]
   LoginReq(LoginReq),
   #[from]
+  Resume(ResumeReq),
+  #[from]
+  Logout(LogoutReq),
+  #[from]
   ListProjectsReq(ListProjectsReq),
   #[from]
   CreateProject(CreateProjectReq),
@@ -113,6 +361,8 @@ pub enum ReqKind {
   #[from]
   CloseProject(CloseProjectReq),
   #[from]
+  ListFiles(ListFilesReq),
+  #[from]
   CreateFile(CreateFileReq),
   #[from]
   DeleteFile(DeleteFileReq),
@@ -124,6 +374,50 @@ pub enum ReqKind {
   CloseFile(CloseFileReq)
 }
 
+impl ReqKind {
+  /// A failed response of the type this request expects
+  pub fn reject(&self, error: Error) -> ResKind {
+    match self {
+      Self::Hello(_) => HelloRes::error(error).into(),
+      Self::LoginReq(_) => LoginRes::error(error).into(),
+      Self::Resume(_) => ResumeRes::error(error).into(),
+      Self::Logout(_) => LogoutRes::error(error).into(),
+      Self::ListProjectsReq(_) => ListProjectsRes::error(error).into(),
+      Self::CreateProject(_) => CreateProjectRes::error(error).into(),
+      Self::DeleteProject(_) => DeleteProjectRes::error(error).into(),
+      Self::OpenProject(_) => OpenProjectRes::error(error).into(),
+      Self::CloseProject(_) => CloseProjectRes::error(error).into(),
+      Self::ListFiles(_) => ListFilesRes::error(error).into(),
+      Self::CreateFile(_) => CreateFileRes::error(error).into(),
+      Self::DeleteFile(_) => DeleteFileRes::error(error).into(),
+      Self::OpenFile(_) => OpenFileRes::error(error).into(),
+      Self::UpdateFile(_) => UpdateFileRes::error(error).into(),
+      Self::CloseFile(_) => CloseFileRes::error(error).into()
+    }
+  }
+
+  /// The request's `type` as it appears on the wire
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Hello(_) => "hello",
+      Self::LoginReq(_) => "login_req",
+      Self::Resume(_) => "resume",
+      Self::Logout(_) => "logout",
+      Self::ListProjectsReq(_) => "list_projects_req",
+      Self::CreateProject(_) => "create_project",
+      Self::DeleteProject(_) => "delete_project",
+      Self::OpenProject(_) => "open_project",
+      Self::CloseProject(_) => "close_project",
+      Self::ListFiles(_) => "list_files",
+      Self::CreateFile(_) => "create_file",
+      Self::DeleteFile(_) => "delete_file",
+      Self::OpenFile(_) => "open_file",
+      Self::UpdateFile(_) => "update_file",
+      Self::CloseFile(_) => "close_file"
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Req {
   pub id: u64,
@@ -139,13 +433,181 @@ impl Req {
   }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloRes {
+  pub success: bool,
+  pub error: Option<Error>,
+  /// Always the server's version, so a rejected client can report the mismatch
+  pub version: u32,
+  pub capabilities: Option<Capabilities>,
+
+  /// The encoding used for every frame after this one
+  pub encoding: Encoding
+}
+
+impl HelloRes {
+  pub fn success(capabilities: Capabilities, encoding: Encoding) -> Self {
+    Self {
+      success: true,
+      error: None,
+      version: PROTOCOL_VERSION,
+      capabilities: Some(capabilities),
+      encoding
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      error: Some(error.into()),
+      version: PROTOCOL_VERSION,
+      capabilities: None,
+      encoding: Encoding::Json
+    }
+  }
+}
+
+/// Several requests sent in a single frame
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+  pub reqs: Vec<Req>,
+
+  /// Whether the requests may be processed concurrently instead of in order
+  #[serde(default)]
+  pub independent: bool
+}
+
+/// The contents of a frame sent by the client
+#[derive(Debug, Deserialize)]
+#[serde(from = "RawFrame")]
+pub enum Frame {
+  Req(Req),
+  Batch(Batch)
+}
+
+/// The shapes a `Frame` may take, for decoding one without going through JSON values
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawFrame {
+  Req(Req),
+  Batch(Batch),
+  Reqs(Vec<Req>)
+}
+
+impl From<RawFrame> for Frame {
+  fn from(raw: RawFrame) -> Self {
+    match raw {
+      RawFrame::Req(req) => Self::Req(req),
+      RawFrame::Batch(batch) => Self::Batch(batch),
+      RawFrame::Reqs(reqs) => Self::Batch(Batch {
+        reqs,
+        independent: false
+      })
+    }
+  }
+}
+
+impl Frame {
+  /// Parses a JSON frame holding a `Req`, an array of `Req`s (processed in order) or a `Batch`.
+  /// Errors are more specific than deserializing a `Frame` directly, which can only tell
+  /// that none of the shapes matched.
+  pub fn parse(text: &str) -> serde_json::Result<Self> {
+    Self::from_value(serde_json::from_str(text)?)
+  }
+
+  /// Interprets an already decoded frame. See `parse`.
+  pub fn from_value(value: serde_json::Value) -> serde_json::Result<Self> {
+    if value.is_array() {
+      Ok(Self::Batch(Batch {
+        reqs: serde_json::from_value(value)?,
+        independent: false
+      }))
+    } else if value.get("reqs").is_some() {
+      Ok(Self::Batch(serde_json::from_value(value)?))
+    } else {
+      Ok(Self::Req(serde_json::from_value(value)?))
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRes {
   pub success: bool,
-  pub error: Option<String>
+  pub error: Option<Error>,
+
+  /// Presented in a `ResumeReq` to log in again without the password
+  pub token: Option<String>,
+
+  /// When `token` expires, in seconds since the Unix epoch
+  pub expires: Option<u64>
 }
 
 impl LoginRes {
+  pub fn success(token: String, expires: u64) -> Self {
+    Self {
+      success: true,
+      error: None,
+      token: Some(token),
+      expires: Some(expires)
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      error: Some(error.into()),
+      token: None,
+      expires: None
+    }
+  }
+}
+
+/// A file left open by a dropped connection, now usable through the same handle
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReattachedFile {
+  pub handle: u64,
+  #[serde(with = "uuid_string")]
+  pub project: Uuid,
+  pub path: ProjectPath,
+  pub version: u64,
+  /// The latest contents sent for the file, which may not have been flushed yet
+  pub contents: Contents
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeRes {
+  pub success: bool,
+  pub error: Option<Error>,
+
+  /// Files reattached from a previous connection that presented the same token
+  pub files: Option<Vec<ReattachedFile>>
+}
+
+impl ResumeRes {
+  pub fn success(files: Vec<ReattachedFile>) -> Self {
+    Self {
+      success: true,
+      error: None,
+      files: Some(files)
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      error: Some(error.into()),
+      files: None
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutRes {
+  pub success: bool,
+  pub error: Option<Error>
+}
+
+impl LogoutRes {
   pub fn success() -> Self {
     Self {
       success: true,
@@ -153,10 +615,10 @@ impl LoginRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
     }
   }
 }
@@ -165,7 +627,7 @@ impl LoginRes {
 pub struct ListProjectsRes {
   pub success: bool,
   pub projects: Option<Vec<ProjectBrief>>,
-  pub error: Option<String>
+  pub error: Option<Error>
 }
 
 impl ListProjectsRes {
@@ -177,11 +639,83 @@ impl ListProjectsRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
       projects: None,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateProjectRes {
+  pub success: bool,
+  pub project: Option<ProjectBrief>,
+  pub error: Option<Error>
+}
+
+impl CreateProjectRes {
+  pub fn success(project: ProjectBrief) -> Self {
+    Self {
+      success: true,
+      project: Some(project),
+      error: None,
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      project: None,
+      error: Some(error.into()),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteProjectRes {
+  pub success: bool,
+  pub error: Option<Error>
+}
+
+impl DeleteProjectRes {
+  pub fn success() -> Self {
+    Self {
+      success: true,
+      error: None,
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      error: Some(error.into()),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListFilesRes {
+  pub success: bool,
+  pub entries: Option<Vec<EntryInfo>>,
+  pub error: Option<Error>
+}
+
+impl ListFilesRes {
+  pub fn success(entries: Vec<EntryInfo>) -> Self {
+    Self {
+      success: true,
+      entries: Some(entries),
+      error: None,
+    }
+  }
+
+  pub fn error<E: Into<Error>>(error: E) -> Self {
+    Self {
+      success: false,
+      entries: None,
+      error: Some(error.into()),
     }
   }
 }
@@ -189,24 +723,21 @@ impl ListProjectsRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateFileRes {
   pub success: bool,
-  pub uuid: Option<Uuid>,
-  pub error: Option<String>
+  pub error: Option<Error>
 }
 
 impl CreateFileRes {
-  pub fn success(uuid: Uuid) -> Self {
+  pub fn success() -> Self {
     Self {
       success: true,
-      uuid: Some(uuid),
       error: None,
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      uuid: None,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
     }
   }
 }
@@ -214,12 +745,12 @@ impl CreateFileRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeleteFileRes {
   pub success: bool,
-  pub contents: Option<String>,
-  pub error: Option<String>
+  pub contents: Option<Contents>,
+  pub error: Option<Error>
 }
 
 impl DeleteFileRes {
-  pub fn success<S: Into<String>>(contents: S) -> Self {
+  pub fn success<C: Into<Contents>>(contents: C) -> Self {
     Self {
       success: true,
       contents: Some(contents.into()),
@@ -227,11 +758,11 @@ impl DeleteFileRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
       contents: None,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
     }
   }
 }
@@ -239,7 +770,7 @@ impl DeleteFileRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenProjectRes {
   pub success: bool,
-  pub error: Option<String>
+  pub error: Option<Error>
 }
 
 impl OpenProjectRes {
@@ -250,10 +781,10 @@ impl OpenProjectRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
     }
   }
 }
@@ -261,7 +792,7 @@ impl OpenProjectRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CloseProjectRes {
   pub success: bool,
-  pub error: Option<String>
+  pub error: Option<Error>
 }
 
 impl CloseProjectRes {
@@ -272,10 +803,10 @@ impl CloseProjectRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
     }
   }
 }
@@ -283,27 +814,35 @@ impl CloseProjectRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenFileRes {
   pub success: bool,
-  pub error: Option<String>,
-  pub contents: Option<String>,
-  pub handle: Option<u64>
+  pub error: Option<Error>,
+  /// Text as a string, binary data as bytes (or `{ "base64": "..." }` in JSON)
+  pub contents: Option<Contents>,
+  pub handle: Option<u64>,
+  /// Incremented by every `UpdateFileReq` that carries code
+  pub version: Option<u64>,
+  pub mime: Option<String>
 }
 
 impl OpenFileRes {
-  pub fn success<C: Into<String>>(handle: u64, contents: C) -> Self {
+  pub fn success<C: Into<Contents>, M: Into<String>>(handle: u64, contents: C, version: u64, mime: M) -> Self {
     Self {
       success: true,
       error: None,
       contents: Some(contents.into()),
-      handle: Some(handle)
+      handle: Some(handle),
+      version: Some(version),
+      mime: Some(mime.into())
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string()),
+      error: Some(error.into()),
       contents: None,
-      handle: None
+      handle: None,
+      version: None,
+      mime: None
     }
   }
 }
@@ -311,24 +850,32 @@ impl OpenFileRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateFileRes {
   pub success: bool,
-  pub error: Option<String>,
-  pub messages: Option<Vec<Message>>
+  pub error: Option<Error>,
+  pub messages: Option<Vec<Message>>,
+  /// The target the messages were computed for
+  pub target: Option<Target>,
+  /// The file's version after the update
+  pub version: Option<u64>
 }
 
 impl UpdateFileRes {
-  pub fn success(messages: Vec<Message>) -> Self {
+  pub fn success(messages: Vec<Message>, target: Target, version: u64) -> Self {
     Self {
       success: true,
       error: None,
-      messages: Some(messages)
+      messages: Some(messages),
+      target: Some(target),
+      version: Some(version)
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string()),
-      messages: None
+      error: Some(error.into()),
+      messages: None,
+      target: None,
+      version: None
     }
   }
 }
@@ -336,7 +883,7 @@ impl UpdateFileRes {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CloseFileRes {
   pub success: bool,
-  pub error: Option<String>
+  pub error: Option<Error>
 }
 
 impl CloseFileRes {
@@ -347,10 +894,10 @@ impl CloseFileRes {
     }
   }
 
-  pub fn error<E: Display>(error: E) -> Self {
+  pub fn error<E: Into<Error>>(error: E) -> Self {
     Self {
       success: false,
-      error: Some(error.to_string())
+      error: Some(error.into())
     }
   }
 }
@@ -358,15 +905,27 @@ impl CloseFileRes {
 #[derive(Debug, From, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ResKind {
+  #[from]
+  Hello(HelloRes),
   #[from]
   Login(LoginRes),
   #[from]
+  Resume(ResumeRes),
+  #[from]
+  Logout(LogoutRes),
+  #[from]
   ListProjects(ListProjectsRes),
   #[from]
+  CreateProject(CreateProjectRes),
+  #[from]
+  DeleteProject(DeleteProjectRes),
+  #[from]
   OpenProject(OpenProjectRes),
   #[from]
   CloseProject(CloseProjectRes),
   #[from]
+  ListFiles(ListFilesRes),
+  #[from]
   CreateFile(CreateFileRes),
   #[from]
   DeleteFile(DeleteFileRes),
@@ -378,21 +937,203 @@ pub enum ResKind {
   CloseFile(CloseFileRes)
 }
 
+impl ResKind {
+  /// Why the request failed, if it did
+  pub fn error(&self) -> Option<&Error> {
+    match self {
+      Self::Hello(res) => res.error.as_ref(),
+      Self::Login(res) => res.error.as_ref(),
+      Self::Resume(res) => res.error.as_ref(),
+      Self::Logout(res) => res.error.as_ref(),
+      Self::ListProjects(res) => res.error.as_ref(),
+      Self::CreateProject(res) => res.error.as_ref(),
+      Self::DeleteProject(res) => res.error.as_ref(),
+      Self::OpenProject(res) => res.error.as_ref(),
+      Self::CloseProject(res) => res.error.as_ref(),
+      Self::ListFiles(res) => res.error.as_ref(),
+      Self::CreateFile(res) => res.error.as_ref(),
+      Self::DeleteFile(res) => res.error.as_ref(),
+      Self::OpenFile(res) => res.error.as_ref(),
+      Self::UpdateFile(res) => res.error.as_ref(),
+      Self::CloseFile(res) => res.error.as_ref()
+    }
+  }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Res {
   pub id: u64,
   pub kind: ResKind
 }
 
-impl Res {
-  pub fn as_ws_msg(&self) -> tungstenite::Message {
-    tungstenite::Message::Text(serde_json::to_string(&self).unwrap())
-  }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChange {
+  Created,
+  Modified,
+  Deleted
+}
+
+/// Fresh diagnostics for an open file, computed without a request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiagnosticsNotification {
+  pub handle: u64,
+  pub messages: Vec<Message>,
+  pub target: Target
+}
+
+/// A file in an open project changed outside of this connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChangedNotification {
+  #[serde(with = "uuid_string")]
+  pub project: Uuid,
+  pub path: ProjectPath,
+  pub change: FileChange
+}
+
+/// The session behind a handle has terminated. The handle is no longer valid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionDiedNotification {
+  pub handle: u64,
+  pub reason: String
+}
+
+#[derive(Debug, From, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+  #[from]
+  Diagnostics(DiagnosticsNotification),
+  #[from]
+  FileChanged(FileChangedNotification),
+  #[from]
+  SessionDied(SessionDiedNotification)
 }
 
+/// Sent in place of a response when a frame couldn't be understood
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ProtocolError {
+  /// The id of the offending request, if it could be recovered
+  pub id: Option<u64>,
+  pub error: Error
+}
+
+#[derive(Debug, From, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Msg {
+  #[from]
   Req(Req),
-  Res(Res)
-}
\ No newline at end of file
+  #[from]
+  Res(Res),
+  /// Replies to a `Batch`, in the order the requests were given
+  #[from]
+  Batch(Vec<Res>),
+  /// Pushed by the server without a corresponding request
+  #[from]
+  Notification(Notification),
+  #[from]
+  Error(ProtocolError)
+}
+
+impl Msg {
+  pub fn as_ws_msg(&self, encoding: Encoding) -> anyhow::Result<tungstenite::Message> {
+    encoding.encode(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ids(batch: &Batch) -> Vec<u64> {
+    batch.reqs.iter().map(|req| req.id).collect()
+  }
+
+  #[test]
+  fn parses_single_requests() {
+    match Frame::parse(r#"{"id": 7, "kind": {"type": "logout"}}"#).unwrap() {
+      Frame::Req(Req { id: 7, kind: ReqKind::Logout(_) }) => {},
+      frame => panic!("parsed {:?}", frame)
+    }
+  }
+
+  #[test]
+  fn parses_arrays_as_ordered_batches() {
+    let text = r#"[{"id": 1, "kind": {"type": "logout"}}, {"id": 2, "kind": {"type": "list_projects_req"}}]"#;
+
+    match Frame::parse(text).unwrap() {
+      Frame::Batch(batch) => {
+        assert_eq!(ids(&batch), vec![1, 2]);
+        assert!(!batch.independent);
+      },
+      frame => panic!("parsed {:?}", frame)
+    }
+  }
+
+  #[test]
+  fn parses_batches() {
+    let text = r#"{"reqs": [{"id": 1, "kind": {"type": "logout"}}], "independent": true}"#;
+    match Frame::parse(text).unwrap() {
+      Frame::Batch(batch) => {
+        assert_eq!(ids(&batch), vec![1]);
+        assert!(batch.independent);
+      },
+      frame => panic!("parsed {:?}", frame)
+    }
+
+    // In order unless stated otherwise
+    match Frame::parse(r#"{"reqs": []}"#).unwrap() {
+      Frame::Batch(batch) => assert!(batch.reqs.is_empty() && !batch.independent),
+      frame => panic!("parsed {:?}", frame)
+    }
+  }
+
+  #[test]
+  fn rejects_malformed_frames() {
+    for text in &[
+      "",
+      "not json",
+      r#"{"id": 1}"#,
+      r#"{"id": 1, "kind": {"type": "no_such_request"}}"#,
+      r#"[{"id": 1, "kind": {"type": "logout"}}, 2]"#,
+      r#"{"reqs": {"id": 1, "kind": {"type": "logout"}}}"#
+    ] {
+      assert!(Frame::parse(text).is_err(), "{:?} parsed", text);
+    }
+  }
+
+  fn code(error: anyhow::Error) -> ErrorCode {
+    Error::from(error).code
+  }
+
+  fn io(kind: ErrorKind) -> anyhow::Error {
+    anyhow::Error::from(std::io::Error::from(kind))
+  }
+
+  #[test]
+  fn classifies_errors_anywhere_in_the_chain() {
+    assert_eq!(code(anyhow::Error::from(SpawnError::NoInc).context("Failed to open main.txt")), ErrorCode::NoInc);
+    assert_eq!(code(anyhow::Error::from(ReadError::UnsupportedEntry).context("Failed to read")), ErrorCode::UnsupportedEntry);
+    assert_eq!(code(anyhow::Error::from(TokenError::Expired).context("Failed to resume")), ErrorCode::AuthFailed);
+    assert_eq!(code(anyhow::Error::from(TokenError::Revoked)), ErrorCode::AuthFailed);
+
+    let escape = PathError::Escapes(PathBuf::from("link"));
+    assert_eq!(code(anyhow::Error::from(escape).context("Failed to save").context("Request failed")), ErrorCode::InvalidPath);
+  }
+
+  #[test]
+  fn only_missing_files_are_not_found() {
+    assert_eq!(code(io(ErrorKind::NotFound).context("Failed to open project")), ErrorCode::NotFound);
+    assert_eq!(code(io(ErrorKind::PermissionDenied).context("Failed to save")), ErrorCode::Internal);
+  }
+
+  #[test]
+  fn keeps_the_outermost_message() {
+    let error = Error::from(io(ErrorKind::NotFound).context("Project does not exist"));
+    assert_eq!(error.message, "Project does not exist");
+    assert!(error.details.is_none());
+
+    let error = Error::from(anyhow::anyhow!("Something broke"));
+    assert_eq!(error.code, ErrorCode::Internal);
+    assert_eq!(error.message, "Something broke");
+  }
+}
@@ -0,0 +1,287 @@
+//! Limits on what a single user or connection may use (see `config::Limits`).
+//! Exceeding one fails the request with `ErrorCode::QuotaExceeded`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+use crate::config;
+use crate::proto::Error;
+
+lazy_static! {
+  /// Resources held by each user across all of their connections, keyed by `Ident`
+  static ref USAGE: Mutex<HashMap<String, Usage>> = Mutex::new(HashMap::new());
+
+  /// Each user's request rate, shared by their connections while any of them hold it
+  static ref RATES: Mutex<HashMap<String, Weak<Mutex<RateLimiter>>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+  sessions: usize,
+  builds: usize
+}
+
+impl Usage {
+  fn held(&mut self, resource: Resource) -> &mut usize {
+    match resource {
+      Resource::Sessions => &mut self.sessions,
+      Resource::Builds => &mut self.builds
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Resource {
+  Sessions,
+  Builds
+}
+
+/// A unit of a per-user resource, returned when dropped
+#[derive(Debug)]
+pub struct Permit {
+  user: String,
+  resource: Resource
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    let mut usage = USAGE.lock().unwrap();
+
+    if let Some(entry) = usage.get_mut(&self.user) {
+      *entry.held(self.resource) -= 1;
+
+      if entry.sessions == 0 && entry.builds == 0 {
+        usage.remove(&self.user);
+      }
+    }
+  }
+}
+
+fn acquire(user: &str, resource: Resource, max: usize, what: &str) -> Result<Permit, Error> {
+  let mut usage = USAGE.lock().unwrap();
+  let held = usage.entry(user.to_string()).or_default().held(resource);
+
+  if *held >= max {
+    return Err(Error::quota_exceeded(format!("At most {} {} are allowed per user", max, what)));
+  }
+
+  *held += 1;
+  Ok(Permit {
+    user: user.to_string(),
+    resource
+  })
+}
+
+/// Held for as long as `user` has a file open with a compiler session
+pub fn session(user: &str) -> Result<Permit, Error> {
+  acquire(user, Resource::Sessions, config::get().limits.max_user_sessions, "open compiler sessions")
+}
+
+/// Held while one of `user`'s files is compiling
+pub fn build(user: &str) -> Result<Permit, Error> {
+  acquire(user, Resource::Builds, config::get().limits.max_concurrent_builds, "concurrent builds")
+}
+
+/// Fails if a connection that already has `open` files open may not open another
+pub fn check_handles(open: usize) -> Result<(), Error> {
+  let max = config::get().limits.max_handles;
+
+  if open >= max {
+    return Err(Error::quota_exceeded(format!("At most {} files may be open per connection", max)));
+  }
+
+  Ok(())
+}
+
+pub fn check_file_size(path: &Path, len: u64) -> Result<(), Error> {
+  let max = config::get().limits.max_file_size;
+
+  if len > max {
+    return Err(Error::quota_exceeded(format!("Files may be at most {} bytes", max)).with_path(path));
+  }
+
+  Ok(())
+}
+
+pub fn check_project_size(total: u64) -> Result<(), Error> {
+  let max = config::get().limits.max_project_size;
+
+  if total > max {
+    return Err(Error::quota_exceeded(format!("Projects may be at most {} bytes", max)));
+  }
+
+  Ok(())
+}
+
+/// A token bucket limiting how often requests may be sent
+#[derive(Debug)]
+pub struct RateLimiter {
+  tokens: f64,
+  last: Instant
+}
+
+impl Default for RateLimiter {
+  fn default() -> Self {
+    Self {
+      tokens: config::get().limits.request_burst as f64,
+      last: Instant::now()
+    }
+  }
+}
+
+impl RateLimiter {
+  /// Takes a token for one request, failing if the bucket is empty
+  pub fn check(&mut self) -> Result<(), Error> {
+    let limits = &config::get().limits;
+
+    let now = Instant::now();
+    let refill = now.duration_since(self.last).as_secs_f64() * limits.requests_per_second;
+    self.tokens = (self.tokens + refill).min(limits.request_burst as f64);
+    self.last = now;
+
+    if self.tokens < 1.0 {
+      return Err(Error::quota_exceeded(format!("At most {} requests per second are allowed", limits.requests_per_second)));
+    }
+
+    self.tokens -= 1.0;
+    Ok(())
+  }
+}
+
+/// The request rate shared by all of `user`'s connections
+pub fn user_rate(user: &str) -> Arc<Mutex<RateLimiter>> {
+  let mut rates = RATES.lock().unwrap();
+
+  if let Some(rate) = rates.get(user).and_then(Weak::upgrade) {
+    return rate;
+  }
+
+  rates.retain(|_, rate| rate.strong_count() > 0);
+  let rate = Arc::new(Mutex::new(RateLimiter::default()));
+  rates.insert(user.to_string(), Arc::downgrade(&rate));
+  rate
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use crate::proto::ErrorCode;
+
+  /// Tests share the usage table, so each takes permits for a user of its own
+  fn user() -> String {
+    config::init(config::Config::default());
+    uuid::Uuid::new_v4().to_string()
+  }
+
+  fn exceeded<T: std::fmt::Debug>(result: Result<T, Error>) -> bool {
+    matches!(result, Err(Error { code: ErrorCode::QuotaExceeded, .. }))
+  }
+
+  #[test]
+  fn limits_builds_per_user() {
+    let (alice, bob) = (user(), user());
+    let max = config::get().limits.max_concurrent_builds;
+
+    let mut permits: Vec<_> = (0..max).map(|_| build(&alice).unwrap()).collect();
+    assert!(exceeded(build(&alice)));
+
+    // Other users have quotas of their own
+    let _bob = build(&bob).unwrap();
+
+    permits.pop();
+    permits.push(build(&alice).unwrap());
+    assert!(exceeded(build(&alice)));
+  }
+
+  #[test]
+  fn limits_sessions_separately_from_builds() {
+    let alice = user();
+    let max = config::get().limits.max_user_sessions;
+
+    let _sessions: Vec<_> = (0..max).map(|_| session(&alice).unwrap()).collect();
+    assert!(exceeded(session(&alice)));
+    let _build = build(&alice).unwrap();
+  }
+
+  #[test]
+  fn forgets_users_once_every_permit_is_returned() {
+    let alice = user();
+
+    let permits = vec![session(&alice).unwrap(), build(&alice).unwrap()];
+    assert!(USAGE.lock().unwrap().contains_key(&alice));
+
+    drop(permits);
+    assert!(!USAGE.lock().unwrap().contains_key(&alice));
+  }
+
+  #[test]
+  fn checks_handles() {
+    user();
+    let max = config::get().limits.max_handles;
+
+    assert!(check_handles(0).is_ok());
+    assert!(check_handles(max - 1).is_ok());
+    assert!(exceeded(check_handles(max)));
+  }
+
+  #[test]
+  fn checks_sizes() {
+    user();
+    let limits = &config::get().limits;
+
+    assert!(check_file_size(Path::new("main.c"), limits.max_file_size).is_ok());
+    match check_file_size(Path::new("main.c"), limits.max_file_size + 1) {
+      Err(Error { code: ErrorCode::QuotaExceeded, details: Some(details), .. }) => {
+        assert_eq!(details.path.as_deref(), Some(Path::new("main.c")));
+      },
+      result => panic!("{:?}", result)
+    }
+
+    assert!(check_project_size(limits.max_project_size).is_ok());
+    assert!(exceeded(check_project_size(limits.max_project_size + 1)));
+  }
+
+  #[test]
+  fn allows_bursts_then_refills() {
+    user();
+    let limits = &config::get().limits;
+
+    let mut rate = RateLimiter::default();
+    for _ in 0..limits.request_burst {
+      rate.check().unwrap();
+    }
+    assert!(exceeded(rate.check()));
+
+    // A second's worth of tokens
+    rate.last -= Duration::from_secs(1);
+    for _ in 0..(limits.requests_per_second as usize) {
+      rate.check().unwrap();
+    }
+    assert!(exceeded(rate.check()));
+  }
+
+  #[test]
+  fn shares_rates_between_a_users_connections() {
+    let (alice, bob) = (user(), user());
+    let burst = config::get().limits.request_burst;
+
+    let (first, second) = (user_rate(&alice), user_rate(&alice));
+    for _ in 0..burst {
+      first.lock().unwrap().check().unwrap();
+    }
+    assert!(exceeded(second.lock().unwrap().check()));
+
+    // Other users have buckets of their own
+    user_rate(&bob).lock().unwrap().check().unwrap();
+
+    drop((first, second));
+    user_rate(&alice).lock().unwrap().check().unwrap();
+  }
+}
@@ -0,0 +1,93 @@
+use tokio::sync::{mpsc, watch};
+
+/// Resolves when the process is asked to stop: Ctrl-C, or SIGTERM on Unix
+pub async fn signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+      Ok(mut term) => tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = term.recv() => {}
+      },
+      Err(_) => {
+        let _ = tokio::signal::ctrl_c().await;
+      }
+    }
+  }
+
+  #[cfg(not(unix))]
+  {
+    let _ = tokio::signal::ctrl_c().await;
+  }
+}
+
+/// Held by every connection. Tells it when to wind down, and keeps the server
+/// waiting until it's dropped.
+#[derive(Clone)]
+pub struct Shutdown {
+  requested: watch::Receiver<bool>,
+  _alive: mpsc::Sender<()>
+}
+
+impl Shutdown {
+  pub fn requested(&self) -> bool {
+    *self.requested.borrow()
+  }
+
+  /// Resolves once shutdown has been requested
+  pub async fn wait(&mut self) {
+    while !*self.requested.borrow() {
+      if self.requested.changed().await.is_err() {
+        return;
+      }
+    }
+  }
+}
+
+/// Hands out `Shutdown`s and waits for all of them to be dropped
+pub struct Coordinator {
+  request: watch::Sender<bool>,
+  requested: watch::Receiver<bool>,
+  alive: mpsc::Sender<()>,
+  drained: mpsc::Receiver<()>
+}
+
+impl Default for Coordinator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Coordinator {
+  pub fn new() -> Self {
+    let (request, requested) = watch::channel(false);
+    let (alive, drained) = mpsc::channel(1);
+
+    Self {
+      request,
+      requested,
+      alive,
+      drained
+    }
+  }
+
+  pub fn subscribe(&self) -> Shutdown {
+    Shutdown {
+      requested: self.requested.clone(),
+      _alive: self.alive.clone()
+    }
+  }
+
+  /// Asks every connection to wind down and waits until they all have
+  pub async fn shutdown(self) {
+    let Self { request, alive, mut drained, .. } = self;
+
+    let _ = request.send(true);
+    drop(alive);
+
+    // Nothing is ever sent, so this only returns once every sender is gone
+    let _ = drained.recv().await;
+  }
+}
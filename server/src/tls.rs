@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use derive_more::*;
+use tracing::{info, warn};
+use serde::Deserialize;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{ServerConfig, NoClientAuth, internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys}};
+
+/// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Display, Debug, Error)]
+pub enum TlsError {
+  InvalidCert,
+  InvalidKey,
+  NoKey
+}
+
+/// Locations of the PEM-encoded certificate chain and private key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+  pub cert: PathBuf,
+  pub key: PathBuf
+}
+
+impl TlsConfig {
+  fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(&self.cert).ok()?.modified().ok()?;
+    let key = std::fs::metadata(&self.key).ok()?.modified().ok()?;
+    Some((cert, key))
+  }
+
+  fn load(&self) -> anyhow::Result<TlsAcceptor> {
+    let certs = certs(&mut BufReader::new(File::open(&self.cert)?)).map_err(|_| TlsError::InvalidCert)?;
+
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&self.key)?)).map_err(|_| TlsError::InvalidKey)?;
+    if keys.is_empty() {
+      keys = rsa_private_keys(&mut BufReader::new(File::open(&self.key)?)).map_err(|_| TlsError::InvalidKey)?;
+    }
+    let key = keys.into_iter().next().ok_or(TlsError::NoKey)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+  }
+}
+
+/// Terminates TLS for incoming connections, picking up renewed certificates without a restart
+#[derive(Clone)]
+pub struct Tls {
+  acceptor: Arc<RwLock<TlsAcceptor>>
+}
+
+impl Tls {
+  pub fn new(config: TlsConfig) -> anyhow::Result<Self> {
+    let ret = Self {
+      acceptor: Arc::new(RwLock::new(config.load()?))
+    };
+
+    tokio::spawn(reload(config, ret.acceptor.clone()));
+    Ok(ret)
+  }
+
+  pub fn acceptor(&self) -> TlsAcceptor {
+    self.acceptor.read().unwrap().clone()
+  }
+}
+
+async fn reload(config: TlsConfig, acceptor: Arc<RwLock<TlsAcceptor>>) {
+  let mut last = config.modified();
+  let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+  loop {
+    interval.tick().await;
+
+    let modified = config.modified();
+    if modified == last {
+      continue
+    }
+    last = modified;
+
+    // A half-written renewal fails to load; keep serving the old certificate until it's complete
+    match config.load() {
+      Ok(next) => {
+        *acceptor.write().unwrap() = next;
+        info!("Reloaded TLS certificate from {}", config.cert.display());
+      },
+      Err(e) => warn!("Failed to reload TLS certificate from {}: {}", config.cert.display(), e)
+    }
+  }
+}
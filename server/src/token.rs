@@ -0,0 +1,218 @@
+//! Signed session tokens, so a client can resume as the same user on a new
+//! connection without resending its password.
+//!
+//! A token is `<claims>.<signature>`, both base64url-encoded, where the claims
+//! are JSON and the signature is an HMAC-SHA256 of the encoded claims under
+//! the server's secret. Revocations are kept in memory until the token expires.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use derive_more::*;
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config;
+use crate::proto::Ident;
+
+lazy_static! {
+  static ref SECRET: Vec<u8> = match &config::get().auth.secret {
+    Some(secret) => secret.as_bytes().to_vec(),
+    None => {
+      warn!("No auth.secret is configured, so session tokens won't survive a restart");
+      rand::random::<[u8; 32]>().to_vec()
+    }
+  };
+
+  /// Revoked token ids and when they expire anyway
+  static ref REVOKED: Mutex<HashMap<Uuid, u64>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Display, Debug, Error)]
+pub enum TokenError {
+  #[display(fmt = "Malformed session token")]
+  Malformed,
+  #[display(fmt = "Invalid session token")]
+  BadSignature,
+  #[display(fmt = "Session token has expired")]
+  Expired,
+  #[display(fmt = "Session token has been revoked")]
+  Revoked
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+  pub ident: Ident,
+
+  /// Expiry, in seconds since the Unix epoch
+  pub exp: u64,
+
+  /// Identifies the token for revocation
+  pub jti: Uuid
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn mac() -> Hmac<Sha256> {
+  Hmac::<Sha256>::new_from_slice(&SECRET).expect("HMAC accepts keys of any length")
+}
+
+/// Signs already encoded claims
+fn sign(encoded: String) -> String {
+  let mut mac = mac();
+  mac.update(encoded.as_bytes());
+  let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+  format!("{}.{}", encoded, signature)
+}
+
+/// Issues a token for `ident`, returning it with its claims
+pub fn issue(ident: Ident) -> anyhow::Result<(String, Claims)> {
+  let ttl = Duration::from_secs(config::get().auth.token_ttl);
+  let claims = Claims {
+    ident,
+    exp: now() + ttl.as_secs(),
+    jti: Uuid::new_v4()
+  };
+
+  let encoded = base64::encode_config(serde_json::to_vec(&claims)?, base64::URL_SAFE_NO_PAD);
+  Ok((sign(encoded), claims))
+}
+
+/// Checks the signature, expiry and revocation of `token`
+pub fn verify(token: &str) -> Result<Claims, TokenError> {
+  let (encoded, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+  let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| TokenError::Malformed)?;
+
+  let mut mac = mac();
+  mac.update(encoded.as_bytes());
+  mac.verify(&signature).map_err(|_| TokenError::BadSignature)?;
+
+  let claims = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_| TokenError::Malformed)?;
+  let claims: Claims = serde_json::from_slice(&claims).map_err(|_| TokenError::Malformed)?;
+
+  if claims.exp <= now() {
+    return Err(TokenError::Expired);
+  }
+
+  if REVOKED.lock().unwrap().contains_key(&claims.jti) {
+    return Err(TokenError::Revoked);
+  }
+
+  Ok(claims)
+}
+
+/// Prevents the token from being used again
+pub fn revoke(claims: &Claims) {
+  let now = now();
+  let mut revoked = REVOKED.lock().unwrap();
+
+  // Expired tokens are rejected regardless, so there's no need to remember them
+  revoked.retain(|_, exp| *exp > now);
+  revoked.insert(claims.jti, claims.exp);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn alice() -> Ident {
+    config::init(config::Config::default());
+    Ident::username("alice")
+  }
+
+  fn encode(claims: &Claims) -> String {
+    base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD)
+  }
+
+  #[test]
+  fn verifies_issued_tokens() {
+    let (token, issued) = issue(alice()).unwrap();
+    let claims = verify(&token).unwrap();
+
+    assert_eq!(claims.ident.to_string(), issued.ident.to_string());
+    assert_eq!(claims.jti, issued.jti);
+    assert_eq!(claims.exp, issued.exp);
+    assert!(claims.exp > now());
+  }
+
+  #[test]
+  fn issues_distinct_tokens() {
+    let (first, _) = issue(alice()).unwrap();
+    let (second, _) = issue(alice()).unwrap();
+    assert_ne!(first, second);
+  }
+
+  #[test]
+  fn rejects_malformed_tokens() {
+    alice();
+    // Correctly signed, but not claims
+    let not_json = sign(base64::encode_config("not json", base64::URL_SAFE_NO_PAD));
+
+    for token in &["", "no-signature", "claims.!!!", not_json.as_str()] {
+      assert!(matches!(verify(token), Err(TokenError::Malformed)), "{:?} verified", token);
+    }
+  }
+
+  #[test]
+  fn rejects_tampered_tokens() {
+    let (token, mut claims) = issue(alice()).unwrap();
+    let (encoded, signature) = token.split_once('.').unwrap();
+
+    // Someone else's claims under alice's signature
+    claims.ident = Ident::username("mallory");
+    let forged = format!("{}.{}", encode(&claims), signature);
+    assert!(matches!(verify(&forged), Err(TokenError::BadSignature)));
+
+    // A signature that's been altered
+    let mut altered = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+    altered[0] ^= 1;
+    let altered = format!("{}.{}", encoded, base64::encode_config(altered, base64::URL_SAFE_NO_PAD));
+    assert!(matches!(verify(&altered), Err(TokenError::BadSignature)));
+  }
+
+  #[test]
+  fn rejects_expired_tokens() {
+    let claims = Claims {
+      ident: alice(),
+      exp: now() - 1,
+      jti: Uuid::new_v4()
+    };
+
+    assert!(matches!(verify(&sign(encode(&claims))), Err(TokenError::Expired)));
+  }
+
+  #[test]
+  fn rejects_revoked_tokens() {
+    let (token, claims) = issue(alice()).unwrap();
+    let (other, _) = issue(alice()).unwrap();
+
+    revoke(&claims);
+    assert!(matches!(verify(&token), Err(TokenError::Revoked)));
+    assert!(verify(&other).is_ok());
+  }
+
+  #[test]
+  fn forgets_revocations_once_expired() {
+    let expired = Claims {
+      ident: alice(),
+      exp: now() - 1,
+      jti: Uuid::new_v4()
+    };
+    revoke(&expired);
+
+    let (_, claims) = issue(alice()).unwrap();
+    revoke(&claims);
+
+    let revoked = REVOKED.lock().unwrap();
+    assert!(!revoked.contains_key(&expired.jti));
+    assert!(revoked.contains_key(&claims.jti));
+  }
+}
@@ -0,0 +1,598 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, field, info_span, warn, Instrument};
+use tungstenite::{Message, protocol::{CloseFrame, Role, frame::coding::CloseCode}};
+
+use crate::backing::Backing;
+use crate::conn::Connection;
+use crate::metrics::{self, GaugeGuard};
+use crate::shutdown::Shutdown;
+use crate::proto::*;
+use crate::config::ConnectionConfig;
+use crate::{lsp, Notifier};
+
+/// A client's byte stream, either plain TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type WebSocket = WebSocketStream<Box<dyn Stream>>;
+
+/// Queues frames to be written to a client by the connection's writer task
+pub type Outbox = UnboundedSender<Message>;
+
+/// The protocol spoken over a websocket, selected by the request path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WireMode {
+  /// `proto::Msg`, JSON unless another encoding is negotiated
+  Native,
+  /// JSON-RPC 2.0 with LSP methods, on `/lsp`
+  Lsp
+}
+
+impl WireMode {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::Native => "native",
+      Self::Lsp => "lsp"
+    }
+  }
+
+  fn from_path(path: &str) -> Self {
+    match path.trim_end_matches('/') {
+      "/lsp" => Self::Lsp,
+      _ => Self::Native
+    }
+  }
+}
+
+/// Sent to every client when the server stops
+pub fn going_away() -> Message {
+  Message::Close(Some(CloseFrame {
+    code: CloseCode::Away,
+    reason: "Server is shutting down".into()
+  }))
+}
+
+/// Sent to a client that hasn't made a request in too long
+pub fn idle() -> Message {
+  Message::Close(Some(CloseFrame {
+    code: CloseCode::Normal,
+    reason: "Idle timeout".into()
+  }))
+}
+
+/// Why `Keepalive::next` resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+  /// Time to ping the client
+  Ping,
+  /// Nothing, not even a pong, has arrived since well before the last ping
+  Dead,
+  /// The client is alive but hasn't made a request within the idle timeout
+  Idle
+}
+
+/// Decides when to ping a client and when to give up on it (see `ConnectionConfig`)
+pub struct Keepalive {
+  ping_interval: Option<Duration>,
+  pong_timeout: Duration,
+  last_ping: Instant,
+  last_frame: Instant,
+  last_request: Instant
+}
+
+impl Keepalive {
+  pub fn new(config: &ConnectionConfig) -> Self {
+    let now = Instant::now();
+
+    Self {
+      ping_interval: match config.ping_interval {
+        0 => None,
+        secs => Some(Duration::from_secs(secs))
+      },
+      pong_timeout: Duration::from_secs(config.pong_timeout),
+      last_ping: now,
+      last_frame: now,
+      last_request: now
+    }
+  }
+
+  /// Records an incoming frame. Anything but a ping or pong counts as a request.
+  pub fn saw(&mut self, msg: &Message) {
+    self.last_frame = Instant::now();
+
+    if !matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+      self.last_request = self.last_frame;
+    }
+  }
+
+  /// Resolves when something needs doing. An `idle_timeout` of 0 seconds never times out.
+  /// Cancel-safe: nothing changes unless it resolves.
+  pub async fn next(&mut self, idle_timeout: u64) -> Liveness {
+    let idle = match idle_timeout {
+      0 => None,
+      secs => Some(self.last_request + Duration::from_secs(secs))
+    };
+    let (ping, dead) = match self.ping_interval {
+      Some(interval) => (Some(self.last_ping + interval), Some(self.last_frame + interval + self.pong_timeout)),
+      None => (None, None)
+    };
+
+    let deadline = match [idle, ping, dead].iter().flatten().min() {
+      Some(deadline) => *deadline,
+      None => return futures_util::future::pending().await
+    };
+    tokio::time::sleep_until(deadline).await;
+
+    let now = Instant::now();
+    if matches!(idle, Some(idle) if now >= idle) {
+      Liveness::Idle
+    } else if matches!(dead, Some(dead) if now >= dead) {
+      Liveness::Dead
+    } else {
+      self.last_ping = now;
+      Liveness::Ping
+    }
+  }
+}
+
+/// Recovers the request id from a frame that didn't parse as a `Req`
+fn recover_id(text: &str) -> Option<u64> {
+  serde_json::from_str::<serde_json::Value>(text).ok()?.get("id")?.as_u64()
+}
+
+/// Serves a client whose websocket handshake on `path` has completed, until it disconnects
+pub async fn serve(
+  stream: Box<dyn Stream>,
+  path: &str,
+  config: ConnectionConfig,
+  backing: Arc<dyn Backing>,
+  shutdown: Shutdown
+) -> anyhow::Result<()> {
+  let websocket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+  let mode = WireMode::from_path(path);
+  let _open = GaugeGuard::new(metrics::CONNECTIONS.with_label_values(&[mode.name()]));
+
+  // `user` is recorded once the client logs in
+  let span = info_span!("websocket", mode = mode.name(), user = field::Empty);
+  match mode {
+    WireMode::Native => accept_connection(websocket, config, backing, shutdown).instrument(span).await,
+    WireMode::Lsp => lsp::accept_connection(websocket, config, backing, shutdown).instrument(span).await
+  }
+}
+
+/// Splits `websocket` into a stream of incoming frames and an outbox drained by a writer task.
+/// Notifications are encoded with the latest value of `encoding` as they're written.
+pub fn split(
+  websocket: WebSocket,
+  notifications: UnboundedReceiver<Notification>,
+  encoding: watch::Receiver<Encoding>
+) -> (SplitStream<WebSocket>, Outbox, tokio::task::JoinHandle<anyhow::Result<()>>) {
+  let (sink, stream) = websocket.split();
+  let (outbox, outgoing) = unbounded_channel();
+  let writer = tokio::spawn(write_loop(sink, outgoing, notifications, encoding));
+  (stream, outbox, writer)
+}
+
+/// Writes queued frames and notifications until the outbox closes or a close frame is sent
+async fn write_loop(
+  mut sink: SplitSink<WebSocket, Message>,
+  mut outgoing: UnboundedReceiver<Message>,
+  mut notifications: UnboundedReceiver<Notification>,
+  encoding: watch::Receiver<Encoding>
+) -> anyhow::Result<()> {
+  loop {
+    let msg = tokio::select! {
+      // Replies are queued before the encoding they negotiate takes effect,
+      // so they must be written before any notification encoded after it.
+      biased;
+      msg = outgoing.recv() => match msg {
+        Some(msg) => msg,
+        None => break
+      },
+      Some(notification) = notifications.recv() => Msg::from(notification).as_ws_msg(*encoding.borrow())?
+    };
+
+    let close = msg.is_close();
+    sink.send(msg).await?;
+
+    if close {
+      break
+    }
+  }
+
+  Ok(())
+}
+
+async fn accept_connection(
+  websocket: WebSocket,
+  config: ConnectionConfig,
+  backing: Arc<dyn Backing>,
+  shutdown: Shutdown
+) -> anyhow::Result<()> {
+  let (notifier, notifications): (Notifier, _) = unbounded_channel();
+  let conn = Connection::new(backing, notifier);
+
+  let (encoding, encoding_rx) = watch::channel(Encoding::Json);
+  let (stream, outbox, writer) = split(websocket, notifications, encoding_rx);
+
+  let ret = read_loop(stream, outbox, encoding, &config, &conn, shutdown.clone()).await;
+
+  // In-flight requests have finished, so closing flushes their saves. Files are only
+  // kept for reattaching if the client went away, not the server.
+  if shutdown.requested() {
+    conn.close_all().await;
+  } else {
+    conn.park(shutdown).await;
+  }
+
+  // The outbox was dropped by `read_loop`, so the writer exits once it's flushed
+  let _ = writer.await;
+  ret
+}
+
+async fn read_loop(
+  mut stream: SplitStream<WebSocket>,
+  outbox: Outbox,
+  encoding: watch::Sender<Encoding>,
+  config: &ConnectionConfig,
+  conn: &Connection,
+  mut shutdown: Shutdown
+) -> anyhow::Result<()> {
+  let mut bad_frames = 0usize;
+  let mut keepalive = Keepalive::new(config);
+  let mut greeted = false;
+
+  loop {
+    let idle_timeout = match conn.logged_in().await {
+      true => config.idle_timeout,
+      false => config.login_timeout
+    };
+
+    // Requests are handled to completion before the next frame is read,
+    // so neither shutdown nor a timeout ever interrupts one
+    let msg = tokio::select! {
+      msg = stream.next() => match msg {
+        Some(msg) => msg?,
+        None => break
+      },
+      liveness = keepalive.next(idle_timeout) => match liveness {
+        Liveness::Ping => {
+          outbox.send(Message::Ping(Vec::new()))?;
+          continue
+        },
+        Liveness::Dead => {
+          debug!("Client stopped responding to pings");
+          return Ok(());
+        },
+        Liveness::Idle => {
+          debug!("Closing idle connection");
+          outbox.send(idle())?;
+          return Ok(());
+        }
+      },
+      Some(change) = conn.next_change() => {
+        conn.file_changed(change).await;
+        continue
+      },
+      _ = shutdown.wait() => {
+        outbox.send(going_away())?;
+        return Ok(());
+      }
+    };
+    keepalive.saw(&msg);
+    let current = *encoding.borrow();
+
+    let parsed = match &msg {
+      Message::Text(text) => Frame::parse(text).map_err(|e| ProtocolError {
+        id: recover_id(text),
+        error: Error::new(ErrorCode::MalformedRequest, e.to_string())
+      }),
+      Message::Binary(_) if !current.is_binary() => Err(ProtocolError {
+        id: None,
+        error: Error::new(ErrorCode::MalformedFrame, "Binary frames require a negotiated binary encoding")
+      }),
+      Message::Binary(data) => current.decode(data).map_err(|e| ProtocolError {
+        id: None,
+        error: Error::new(ErrorCode::MalformedRequest, e.to_string())
+      }),
+      Message::Close(_) => return Ok(()),
+      _ => continue
+    };
+
+    // A batched hello would switch encodings partway through the batch's reply, and
+    // couldn't close the connection if it failed, so it has to come on its own
+    let parsed = parsed.and_then(|frame| match &frame {
+      Frame::Batch(batch) => match batch.reqs.iter().find(|req| matches!(req.kind, ReqKind::Hello(_))) {
+        Some(hello) => Err(ProtocolError {
+          id: Some(hello.id),
+          error: Error::new(ErrorCode::MalformedRequest, "A hello can't be sent in a batch")
+        }),
+        None => Ok(frame)
+      },
+      Frame::Req(_) => Ok(frame)
+    });
+
+    let frame = match parsed {
+      Ok(frame) => frame,
+      Err(e) => {
+        bad_frames += 1;
+        warn!("Malformed frame ({} of {}): {}", bad_frames, config.max_bad_frames, e.error);
+        outbox.send(Msg::from(e).as_ws_msg(current)?)?;
+
+        if bad_frames >= config.max_bad_frames {
+          outbox.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "Too many malformed frames".into()
+          })))?;
+          return Ok(());
+        }
+
+        continue
+      }
+    };
+
+    // Nothing else is understood until the client's version is known to be compatible
+    if !greeted && !matches!(&frame, Frame::Req(Req { kind: ReqKind::Hello(_), .. })) {
+      let error = Error::new(ErrorCode::NoHandshake, "The connection must start with a hello");
+      let reply = match &frame {
+        Frame::Req(req) => Msg::from(req.reply(req.kind.reject(error))),
+        Frame::Batch(_) => Msg::from(ProtocolError { id: None, error })
+      };
+      outbox.send(reply.as_ws_msg(current)?)?;
+      outbox.send(Message::Close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: "Handshake required".into()
+      })))?;
+      return Ok(());
+    }
+
+    match frame {
+      Frame::Req(req) => {
+        let res = conn.dispatch(&req).await;
+
+        let hello = match &res.kind {
+          ResKind::Hello(hello) => Some((hello.success, hello.encoding)),
+          _ => None
+        };
+        outbox.send(Msg::from(res).as_ws_msg(current)?)?;
+
+        match hello {
+          // A client that failed the handshake can't understand anything else we'd say
+          Some((false, _)) => {
+            outbox.send(Message::Close(None))?;
+            return Ok(());
+          },
+          // The reply to the handshake is sent in the old encoding, everything after in the new one
+          Some((true, negotiated)) => {
+            greeted = true;
+            let _ = encoding.send(negotiated);
+          },
+          None => {}
+        }
+      },
+      Frame::Batch(batch) => {
+        let res = conn.dispatch_batch(&batch).await;
+        outbox.send(Msg::from(res).as_ws_msg(current)?)?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::{json, Value};
+  use tokio::io::DuplexStream;
+  use tokio::time::{advance, timeout};
+
+  use crate::backing::SimpleBacking;
+  use crate::shutdown::Coordinator;
+
+  /// A client talking to `accept_connection` over an in-memory pipe
+  struct Client {
+    websocket: WebSocketStream<DuplexStream>,
+    _coordinator: Coordinator
+  }
+
+  impl Client {
+    async fn connect() -> Self {
+      crate::config::init(crate::config::Config::default());
+
+      let (client, server) = tokio::io::duplex(1 << 16);
+      let server: Box<dyn Stream> = Box::new(server);
+      let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+
+      // Nothing here logs in, so the backing is never touched
+      let backing = Arc::new(SimpleBacking::new(std::env::temp_dir().join("ivygate-ws-tests")));
+      let coordinator = Coordinator::new();
+      tokio::spawn(accept_connection(server, ConnectionConfig::default(), backing, coordinator.subscribe()));
+
+      Self {
+        websocket: WebSocketStream::from_raw_socket(client, Role::Client, None).await,
+        _coordinator: coordinator
+      }
+    }
+
+    async fn send(&mut self, frame: Value) {
+      self.websocket.send(Message::Text(frame.to_string())).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Message {
+      self.websocket.next().await.unwrap().unwrap()
+    }
+
+    async fn recv_json(&mut self) -> Value {
+      match self.recv().await {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        msg => panic!("expected a text frame, got {:?}", msg)
+      }
+    }
+
+    async fn hello(&mut self) {
+      self.send(json!({ "id": 0, "kind": { "type": "hello", "version": PROTOCOL_VERSION } })).await;
+      assert_eq!(self.recv_json().await["res"]["kind"]["success"], true);
+    }
+  }
+
+  #[tokio::test]
+  async fn requires_a_handshake_first() {
+    let mut client = Client::connect().await;
+
+    client.send(json!({ "id": 1, "kind": { "type": "list_projects_req" } })).await;
+    let reply = client.recv_json().await;
+    assert_eq!(reply["res"]["id"], 1);
+    assert_eq!(reply["res"]["kind"]["error"]["code"], "no_handshake");
+    assert!(matches!(client.recv().await, Message::Close(_)));
+  }
+
+  #[tokio::test]
+  async fn requires_a_handshake_before_batches() {
+    let mut client = Client::connect().await;
+
+    client.send(json!([{ "id": 1, "kind": { "type": "list_projects_req" } }])).await;
+    assert_eq!(client.recv_json().await["error"]["error"]["code"], "no_handshake");
+    assert!(matches!(client.recv().await, Message::Close(_)));
+  }
+
+  #[tokio::test]
+  async fn serves_requests_after_the_handshake() {
+    let mut client = Client::connect().await;
+    client.hello().await;
+
+    client.send(json!({ "id": 1, "kind": { "type": "list_projects_req" } })).await;
+    assert_eq!(client.recv_json().await["res"]["kind"]["error"]["code"], "not_logged_in");
+  }
+
+  #[tokio::test]
+  async fn closes_after_a_failed_handshake() {
+    let mut client = Client::connect().await;
+
+    client.send(json!({ "id": 1, "kind": { "type": "hello", "version": PROTOCOL_VERSION + 1 } })).await;
+    assert_eq!(client.recv_json().await["res"]["kind"]["error"]["code"], "incompatible_version");
+    assert!(matches!(client.recv().await, Message::Close(_)));
+  }
+
+  #[tokio::test]
+  async fn rejects_handshakes_in_batches() {
+    let mut client = Client::connect().await;
+    client.hello().await;
+
+    client.send(json!([
+      { "id": 1, "kind": { "type": "list_projects_req" } },
+      { "id": 2, "kind": { "type": "hello", "version": PROTOCOL_VERSION, "encodings": ["msgpack"] } }
+    ])).await;
+    let reply = client.recv_json().await;
+    assert_eq!(reply["error"]["id"], 2);
+    assert_eq!(reply["error"]["error"]["code"], "malformed_request");
+
+    // Still speaking JSON
+    client.send(json!({ "id": 3, "kind": { "type": "list_projects_req" } })).await;
+    assert_eq!(client.recv_json().await["res"]["id"], 3);
+  }
+
+  #[tokio::test]
+  async fn rejects_failed_handshakes_in_batches() {
+    let mut client = Client::connect().await;
+    client.hello().await;
+
+    client.send(json!([{ "id": 1, "kind": { "type": "hello", "version": PROTOCOL_VERSION + 1 } }])).await;
+    let reply = client.recv_json().await;
+    assert_eq!(reply["error"]["id"], 1);
+    assert_eq!(reply["error"]["error"]["code"], "malformed_request");
+
+    // Rejected before it was handled, so the connection is still usable
+    client.send(json!({ "id": 2, "kind": { "type": "list_projects_req" } })).await;
+    assert_eq!(client.recv_json().await["res"]["id"], 2);
+  }
+
+  fn keepalive(ping_interval: u64, pong_timeout: u64) -> Keepalive {
+    Keepalive::new(&ConnectionConfig {
+      ping_interval,
+      pong_timeout,
+      ..ConnectionConfig::default()
+    })
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn pings_every_interval() {
+    let start = Instant::now();
+    let mut keepalive = keepalive(20, 30);
+
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    assert_eq!(start.elapsed(), Duration::from_secs(20));
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    assert_eq!(start.elapsed(), Duration::from_secs(40));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn gives_up_without_frames() {
+    let start = Instant::now();
+    let mut keepalive = keepalive(20, 20);
+
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    assert_eq!(keepalive.next(0).await, Liveness::Dead);
+    assert_eq!(start.elapsed(), Duration::from_secs(40));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn pongs_keep_clients_alive() {
+    let start = Instant::now();
+    let mut keepalive = keepalive(20, 20);
+
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    advance(Duration::from_secs(10)).await;
+    keepalive.saw(&Message::Pong(Vec::new()));
+
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    assert_eq!(keepalive.next(0).await, Liveness::Ping);
+    assert_eq!(keepalive.next(0).await, Liveness::Dead);
+    assert_eq!(start.elapsed(), Duration::from_secs(70));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn times_out_idle_clients() {
+    let start = Instant::now();
+    let mut keepalive = keepalive(0, 0);
+
+    advance(Duration::from_secs(30)).await;
+    keepalive.saw(&Message::Text("{}".into()));
+    assert_eq!(keepalive.next(60).await, Liveness::Idle);
+    assert_eq!(start.elapsed(), Duration::from_secs(90));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn pings_and_pongs_are_not_requests() {
+    let start = Instant::now();
+    let mut keepalive = keepalive(20, 20);
+
+    loop {
+      match keepalive.next(60).await {
+        Liveness::Ping => keepalive.saw(&Message::Pong(Vec::new())),
+        liveness => {
+          assert_eq!(liveness, Liveness::Idle);
+          break
+        }
+      }
+      keepalive.saw(&Message::Ping(Vec::new()));
+    }
+    assert_eq!(start.elapsed(), Duration::from_secs(60));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn never_resolves_when_disabled() {
+    let mut keepalive = keepalive(0, 0);
+    assert!(timeout(Duration::from_secs(24 * 60 * 60), keepalive.next(0)).await.is_err());
+  }
+}
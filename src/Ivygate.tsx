@@ -31,6 +31,8 @@ export interface IvygateProps extends StyleProps {
   onCodeChange: (code: string) => void;
   theme: string;
   editable?: boolean;
+  /** WebSocket URL of the ivygate server. The editor works offline without one. */
+  server?: string;
 }
 
 interface IvygateState {
@@ -786,8 +788,7 @@ export class Ivygate extends React.PureComponent<Props, State> {
   }
 
   componentDidMount() {
-    server.open('');
-
+    if (this.props.server) server.retain(this.props.server);
   }
 
   private guard_ = false;
@@ -851,7 +852,7 @@ export class Ivygate extends React.PureComponent<Props, State> {
   }
 
   componentWillUnmount() {
-    server.close(0);
+    if (this.props.server) server.release();
   }
 
   revealLineInCenter(line: number) {
@@ -1,91 +1,259 @@
 import Message from './Message';
 
 export namespace Proto {
-  
+  // Must match `proto::PROTOCOL_VERSION` on the server
+  export const VERSION = 1;
+
+  export interface Capabilities {
+    languages: string[];
+    incs: string[];
+    features: string[];
+  }
+
+  export type Ident = { Username: string } | { Email: string };
+
+  export interface User {
+    ident: Ident;
+    password: string;
+  }
+
+  export interface ProjectBrief {
+    uuid: string;
+    name: string;
+  }
+
+  export type Encoding = 'json' | 'msgpack' | 'cbor';
+
+  export interface HelloReq {
+    type: 'hello';
+    version: number;
+    encodings?: Encoding[];
+  }
+
+  export interface LoginReq {
+    type: 'login_req';
+    user: User;
+  }
+
+  export interface ResumeReq {
+    type: 'resume';
+    token: string;
+  }
+
+  export interface LogoutReq {
+    type: 'logout';
+  }
+
+  export interface ListProjectsReq {
+    type: 'list_projects_req';
+  }
+
+  export interface CreateProjectReq {
+    type: 'create_project';
+    name: string;
+  }
+
+  export interface DeleteProjectReq {
+    type: 'delete_project';
+    uuid: string;
+  }
 
-  export interface OpenReq {
-    type: 'open';
+  export interface OpenProjectReq {
+    type: 'open_project';
+    uuid: string;
+  }
+
+  export interface CloseProjectReq {
+    type: 'close_project';
+    uuid: string;
+  }
+
+  // Text as a string, binary data base64-encoded
+  export type Contents = string | { base64: string };
+
+  export interface ListFilesReq {
+    type: 'list_files';
+    project: string;
+    path?: string;
+    depth?: number;
+  }
+
+  export interface CreateFileReq {
+    type: 'create_file';
+    project: string;
+    path: string;
+    contents?: Contents;
+  }
+
+  export interface DeleteFileReq {
+    type: 'delete_file';
+    project: string;
+    path: string;
+  }
+
+  export interface OpenFileReq {
+    type: 'open_file';
+    project: string;
     path: string;
   }
 
-  export interface UpdateReq {
-    type: 'update';
+  export interface UpdateFileReq {
+    type: 'update_file';
     handle: number;
     code?: string;
   }
 
-  export interface CloseReq {
-    type: 'close';
+  export interface CloseFileReq {
+    type: 'close_file';
     handle: number;
   }
 
-  export type ReqKind = OpenReq | UpdateReq | CloseReq;
+  export type ReqKind = HelloReq | LoginReq | ResumeReq | LogoutReq | ListProjectsReq | CreateProjectReq | DeleteProjectReq
+    | OpenProjectReq | CloseProjectReq | ListFilesReq | CreateFileReq | DeleteFileReq | OpenFileReq | UpdateFileReq
+    | CloseFileReq;
 
   export interface Req {
     id: number;
     kind: ReqKind;
   }
 
-  export interface OpenRes {
-    type: 'open';
+  export type ErrorCode = 'no_inc' | 'unsupported_entry' | 'not_found' | 'auth_failed' | 'no_such_handle'
+    | 'not_logged_in' | 'project_not_open' | 'incompatible_version' | 'no_handshake' | 'malformed_frame'
+    | 'malformed_request' | 'quota_exceeded' | 'invalid_path' | 'internal';
+
+  export interface Error {
+    code: ErrorCode;
+    message: string;
+    details?: {
+      path?: string;
+      handle?: number;
+    };
+  }
+
+  interface ResBase {
     success: boolean;
-    error?: string;
-    handle?: number;
+    error?: Error;
   }
 
-  export namespace OpenRes {
-    export const resolveReject = (data: OpenRes, ret: ResolveReject) => {
-      if (!data.success) {
-        ret.reject(data.error);
-        return;
-      }
+  export interface HelloRes extends ResBase {
+    type: 'hello';
+    version: number;
+    capabilities?: Capabilities;
+    encoding: Encoding;
+  }
 
-      ret.resolve(data.handle);
-    };
+  export interface LoginRes extends ResBase {
+    type: 'login';
+    token?: string;
+    // Seconds since the Unix epoch
+    expires?: number;
   }
 
-  export interface UpdateRes {
-    type: 'update';
-    success: boolean;
-    error?: string;
-    messages?: Message[];
+  export interface ReattachedFile {
+    handle: number;
+    project: string;
+    path: string;
+    version: number;
+    contents: Contents;
   }
 
-  export namespace UpdateRes {
-    export const resolveReject = (data: UpdateRes, ret: ResolveReject) => {
-      if (!data.success) {
-        ret.reject(data.error);
-        return;
-      }
+  export interface ResumeRes extends ResBase {
+    type: 'resume';
+    files?: ReattachedFile[];
+  }
 
-      ret.resolve(data.messages);
-    };
+  export interface LogoutRes extends ResBase {
+    type: 'logout';
   }
 
-  export interface CloseRes {
-    type: 'close';
-    success: boolean;
+  export interface ListProjectsRes extends ResBase {
+    type: 'list_projects';
+    projects?: ProjectBrief[];
   }
 
-  export namespace CloseRes {
-    export const resolveReject = (data: CloseRes, ret: ResolveReject) => {
-      if (!data.success) {
-        ret.reject("Failure");
-        return;
-      }
+  export interface OpenProjectRes extends ResBase {
+    type: 'open_project';
+  }
 
-      ret.resolve();
-    };
+  export interface CloseProjectRes extends ResBase {
+    type: 'close_project';
+  }
+
+  export interface CreateProjectRes extends ResBase {
+    type: 'create_project';
+    project?: ProjectBrief;
+  }
+
+  export interface DeleteProjectRes extends ResBase {
+    type: 'delete_project';
   }
 
-  export type ResKind = OpenRes | UpdateRes | CloseRes;
+  export interface EntryInfo {
+    name: string;
+    kind: 'file' | 'folder';
+    size: number;
+    // Guessed from the extension
+    mime?: string;
+    // Seconds since the Unix epoch
+    modified?: number;
+    // Absent for folders below the requested depth
+    entries?: EntryInfo[];
+  }
+
+  export interface ListFilesRes extends ResBase {
+    type: 'list_files';
+    entries?: EntryInfo[];
+  }
+
+  export interface CreateFileRes extends ResBase {
+    type: 'create_file';
+  }
+
+  export interface DeleteFileRes extends ResBase {
+    type: 'delete_file';
+    contents?: Contents;
+  }
+
+  export interface OpenFileRes extends ResBase {
+    type: 'open_file';
+    handle?: number;
+    contents?: Contents;
+    version?: number;
+    mime?: string;
+  }
+
+  export interface UpdateFileRes extends ResBase {
+    type: 'update_file';
+    messages?: Message[];
+    target?: string;
+    version?: number;
+  }
+
+  export interface CloseFileRes extends ResBase {
+    type: 'close_file';
+  }
+
+  export type ResKind = HelloRes | LoginRes | ResumeRes | LogoutRes | ListProjectsRes | CreateProjectRes | DeleteProjectRes
+    | OpenProjectRes | CloseProjectRes | ListFilesRes | CreateFileRes | DeleteFileRes | OpenFileRes | UpdateFileRes | CloseFileRes;
 
   export namespace ResKind {
     export const resolveReject = (data: ResKind, ret: ResolveReject) => {
+      if (!data.success) {
+        ret.reject(data.error);
+        return;
+      }
+
       switch (data.type) {
-        case 'open': return OpenRes.resolveReject(data, ret);
-        case 'update': return UpdateRes.resolveReject(data, ret);
-        case 'close': return CloseRes.resolveReject(data, ret);
+        case 'hello': return ret.resolve(data.capabilities);
+        case 'login': return ret.resolve(data.token);
+        case 'resume': return ret.resolve(data.files);
+        case 'list_projects': return ret.resolve(data.projects);
+        case 'create_project': return ret.resolve(data.project);
+        case 'list_files': return ret.resolve(data.entries);
+        case 'delete_file': return ret.resolve(data.contents);
+        case 'open_file': return ret.resolve(data);
+        case 'update_file': return ret.resolve(data.messages);
+        default: return ret.resolve();
       }
     };
   }
@@ -99,7 +267,41 @@ export namespace Proto {
     export const resolveReject = (data: Res, ret: ResolveReject) => ResKind.resolveReject(data.kind, ret);
   }
 
-  export type Msg = Req | Res;
+  export type FileChange = 'created' | 'modified' | 'deleted';
+
+  export interface DiagnosticsNotification {
+    type: 'diagnostics';
+    handle: number;
+    messages: Message[];
+    target: string;
+  }
+
+  export interface FileChangedNotification {
+    type: 'file_changed';
+    project: string;
+    path: string;
+    change: FileChange;
+  }
+
+  export interface SessionDiedNotification {
+    type: 'session_died';
+    handle: number;
+    reason: string;
+  }
+
+  export type Notification = DiagnosticsNotification | FileChangedNotification | SessionDiedNotification;
+
+  export interface ProtocolError {
+    id?: number;
+    error: Error;
+  }
+
+  export interface Batch {
+    reqs: Req[];
+    independent: boolean;
+  }
+
+  export type Msg = { req: Req } | { res: Res } | { batch: Res[] } | { notification: Notification } | { error: ProtocolError };
 }
 
 interface ResolveReject<T = any> {
@@ -107,41 +309,183 @@ interface ResolveReject<T = any> {
   reject: (err: any) => void;
 }
 
+type NotificationListener = (notification: Proto.Notification) => void;
+type ReattachListener = (files: Proto.ReattachedFile[]) => void;
+
 class Server {
   private iter_ = 0;
   private pending_: { [id: number]: ResolveReject } = {};
+  private listeners_: NotificationListener[] = [];
+  private reattachListeners_: ReattachListener[] = [];
+  private capabilities_: Proto.Capabilities | undefined;
   private socket_: WebSocket;
-  private queued_: Proto.Req[] = [];
+  private queued_: (Proto.Req | Proto.Batch)[] = [];
+  private token_: string | undefined;
+  private url_: string | undefined;
+  private users_ = 0;
 
   connect(url: string) {
+    this.url_ = url;
     this.socket_ = new WebSocket(url);
 
     this.socket_.onopen = this.onOpen_;
     this.socket_.onmessage = this.onMessage_;
     this.socket_.onclose = this.onClose_;
   }
+
+  disconnect() {
+    if (!this.socket_) return;
+
+    // Detached first so a later socket isn't mistaken for this one when it finishes closing
+    this.socket_.onclose = null;
+    this.socket_.close(1000);
+    this.onClose_();
+  }
+
+  /**
+   * Connects to `url` unless already connected there. The socket is shared by every
+   * caller and stays open until each one has called `release`.
+   */
+  retain(url: string) {
+    ++this.users_;
+    if (this.socket_ && this.url_ === url) return;
+
+    this.disconnect();
+    this.connect(url);
+  }
+
+  release() {
+    if (this.users_ === 0) return;
+    if (--this.users_ === 0) this.disconnect();
+  }
   
-  open(path: string) {
-    return this.request<Proto.OpenRes>({
-      type: 'open',
+  get capabilities() {
+    return this.capabilities_;
+  }
+
+  /**
+   * Logs in, remembering the session token so the next connection resumes as the
+   * same user without the password.
+   */
+  async login(user: Proto.User) {
+    this.token_ = await this.request<string>({
+      type: 'login_req',
+      user
+    });
+  }
+
+  async logout() {
+    this.token_ = undefined;
+    await this.request<void>({
+      type: 'logout'
+    });
+  }
+
+  listProjects() {
+    return this.request<Proto.ProjectBrief[]>({
+      type: 'list_projects_req'
+    });
+  }
+
+  createProject(name: string) {
+    return this.request<Proto.ProjectBrief>({
+      type: 'create_project',
+      name
+    });
+  }
+
+  deleteProject(uuid: string) {
+    return this.request<void>({
+      type: 'delete_project',
+      uuid
+    });
+  }
+
+  openProject(uuid: string) {
+    return this.request<void>({
+      type: 'open_project',
+      uuid
+    });
+  }
+
+  closeProject(uuid: string) {
+    return this.request<void>({
+      type: 'close_project',
+      uuid
+    });
+  }
+
+  /**
+   * Lists a folder without file contents, descending `depth` levels (the whole tree if omitted)
+   */
+  listFiles(project: string, path?: string, depth?: number) {
+    return this.request<Proto.EntryInfo[]>({
+      type: 'list_files',
+      project,
+      path,
+      depth
+    });
+  }
+
+  createFile(project: string, path: string, contents?: Proto.Contents) {
+    return this.request<void>({
+      type: 'create_file',
+      project,
+      path,
+      contents
+    });
+  }
+
+  deleteFile(project: string, path: string) {
+    return this.request<Proto.Contents>({
+      type: 'delete_file',
+      project,
       path
-    }); 
+    });
+  }
+
+  openFile(project: string, path: string) {
+    return this.request<Proto.OpenFileRes>({
+      type: 'open_file',
+      project,
+      path
+    });
   }
 
-  update(handle: number) {
+  updateFile(handle: number, code?: string) {
     return this.request<Message[]>({
-      type: 'update',
-      handle
+      type: 'update_file',
+      handle,
+      code
     });
   }
 
-  close(handle: number) {
+  closeFile(handle: number) {
     return this.request<void>({
-      type: 'close',
+      type: 'close_file',
       handle
     });
   }
 
+  addNotificationListener(listener: NotificationListener) {
+    this.listeners_.push(listener);
+  }
+
+  removeNotificationListener(listener: NotificationListener) {
+    this.listeners_ = this.listeners_.filter(l => l !== listener);
+  }
+
+  /**
+   * Called with the files still open from before a reconnect, which keep their handles
+   */
+  addReattachListener(listener: ReattachListener) {
+    this.reattachListeners_.push(listener);
+  }
+
+  removeReattachListener(listener: ReattachListener) {
+    this.reattachListeners_ = this.reattachListeners_.filter(l => l !== listener);
+  }
+
   request<T>(reqKind: Proto.ReqKind): Promise<T> {
     ++this.iter_;
 
@@ -157,7 +501,48 @@ class Server {
     });
   }
 
+  /**
+   * Sends several requests in a single frame. If `independent` is true, the server may
+   * process them concurrently.
+   */
+  batch(reqKinds: Proto.ReqKind[], independent = false): Promise<any>[] {
+    const reqs = reqKinds.map(kind => ({ id: ++this.iter_, kind }));
+    const batch: Proto.Batch = { reqs, independent };
+
+    if (this.socket_ && this.socket_.readyState === WebSocket.OPEN) {
+      this.socket_.send(JSON.stringify(batch));
+    } else {
+      this.queued_.push(batch);
+    }
+
+    return reqs.map(req => new Promise((resolve, reject) => {
+      this.pending_[req.id] = { resolve, reject };
+    }));
+  }
+
   private onOpen_ = (ev: Event) => {
+    const hello: Proto.Req = { id: ++this.iter_, kind: { type: 'hello', version: Proto.VERSION } };
+    this.socket_.send(JSON.stringify(hello));
+    this.pending_[hello.id] = {
+      resolve: (capabilities: Proto.Capabilities) => this.capabilities_ = capabilities,
+      reject: (err: any) => console.error('ivygate server rejected handshake:', err)
+    };
+
+    if (this.token_) {
+      const resume: Proto.Req = { id: ++this.iter_, kind: { type: 'resume', token: this.token_ } };
+      this.socket_.send(JSON.stringify(resume));
+      this.pending_[resume.id] = {
+        resolve: (files: Proto.ReattachedFile[]) => {
+          if (files.length === 0) return;
+          for (const listener of this.reattachListeners_) listener(files);
+        },
+        reject: (err: any) => {
+          console.error('ivygate server rejected session token:', err);
+          this.token_ = undefined;
+        }
+      };
+    }
+
     for (let i = 0; i < this.queued_.length; ++i) {
       const req = this.queued_[i];
       this.socket_.send(JSON.stringify(req));
@@ -166,14 +551,32 @@ class Server {
   };
 
   private onMessage_ = (ev: MessageEvent) => {
-    let res: Proto.Res = JSON.parse(ev.data);
-    
-    if (!(res.id in this.pending_)) return;
-    Proto.Res.resolveReject(res, this.pending_[res.id]);
-    delete this.pending_[res.id];
+    const msg: Proto.Msg = JSON.parse(ev.data);
+
+    if ('notification' in msg) {
+      for (const listener of this.listeners_) listener(msg.notification);
+      return;
+    }
+
+    if ('error' in msg) {
+      const { id, error } = msg.error;
+      console.error('ivygate server could not parse request:', error);
+      if (id === undefined || !(id in this.pending_)) return;
+      this.pending_[id].reject(error);
+      delete this.pending_[id];
+      return;
+    }
+
+    const responses = 'batch' in msg ? msg.batch : 'res' in msg ? [msg.res] : [];
+
+    for (const res of responses) {
+      if (!(res.id in this.pending_)) continue;
+      Proto.Res.resolveReject(res, this.pending_[res.id]);
+      delete this.pending_[res.id];
+    }
   };
 
-  private onClose_ = (ev: Event) => {
+  private onClose_ = (ev?: Event) => {
     const keys = Object.keys(this.pending_);
 
     for (let i = 0; i < keys.length; ++i) {